@@ -0,0 +1,242 @@
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+
+use serde::Deserialize;
+
+const CONFIG_FILE: &str = ".teensy.toml";
+const CARGO_MANIFEST: &str = "Cargo.toml";
+
+/// Environment variables standing in for `--mcu` and the file argument.
+///
+/// For CI and other templated command lines, where the environment is easier to set than
+/// arguments. Arguments still win, and both win over the config file.
+pub const MCU_ENV: &str = "TEENSY_MCU";
+pub const FIRMWARE_ENV: &str = "TEENSY_FIRMWARE";
+
+/// Defaults read from `.teensy.toml` or `[package.metadata.teensy]` in `Cargo.toml`.
+///
+/// Anything given on the command line takes precedence over these.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub mcu: Option<String>,
+    pub file: Option<String>,
+    /// How long to wait for the device to appear, in milliseconds
+    pub timeout: Option<u64>,
+}
+
+impl Config {
+    /// The MCU named by `arg` from the command line, else by `env` from `MCU_ENV`, else by the
+    /// config.
+    pub fn resolve_mcu<'a>(
+        &'a self,
+        arg: Option<&'a str>,
+        env: Option<&'a str>,
+    ) -> Option<&'a str> {
+        arg.or(env).or(self.mcu.as_deref())
+    }
+
+    /// The file given as `arg` on the command line, else as `env` in `FIRMWARE_ENV`, else in the
+    /// config.
+    pub fn resolve_file<'a>(
+        &'a self,
+        arg: Option<&'a str>,
+        env: Option<&'a str>,
+    ) -> Option<&'a str> {
+        arg.or(env).or(self.file.as_deref())
+    }
+}
+
+#[derive(Deserialize)]
+struct CargoManifest {
+    package: Option<CargoPackage>,
+}
+
+#[derive(Deserialize)]
+struct CargoPackage {
+    metadata: Option<CargoMetadata>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadata {
+    teensy: Option<Config>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    FailedRead(&'static str, IoError),
+    Parse(&'static str, toml::de::Error),
+}
+
+/// Looks for a config in `dir`, preferring `.teensy.toml` over `Cargo.toml`.
+///
+/// A missing file is not an error; the default (empty) config is returned instead.
+pub fn load_config(dir: &Path) -> Result<Config, ConfigError> {
+    if let Some(contents) = read_if_exists(dir, CONFIG_FILE)? {
+        return toml::from_str(&contents).map_err(|e| ConfigError::Parse(CONFIG_FILE, e));
+    }
+
+    if let Some(contents) = read_if_exists(dir, CARGO_MANIFEST)? {
+        let manifest: CargoManifest =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(CARGO_MANIFEST, e))?;
+        return Ok(manifest
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.teensy)
+            .unwrap_or_default());
+    }
+
+    Ok(Config::default())
+}
+
+fn read_if_exists(dir: &Path, name: &'static str) -> Result<Option<String>, ConfigError> {
+    match fs::read_to_string(dir.join(name)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ConfigError::FailedRead(name, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Directory under the system temp dir holding the given files, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "rusty_loader_config_{}_{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn missing_file_is_empty() {
+        let dir = TempDir::new("missing", &[]);
+        let config = load_config(&dir.0).unwrap();
+        assert_eq!(config.mcu, None);
+        assert_eq!(config.file, None);
+        assert_eq!(config.timeout, None);
+    }
+
+    #[test]
+    fn teensy_toml() {
+        let dir = TempDir::new(
+            "teensy_toml",
+            &[(
+                CONFIG_FILE,
+                "mcu = \"TEENSY31\"\nfile = \"blink.hex\"\ntimeout = 5000\n",
+            )],
+        );
+        let config = load_config(&dir.0).unwrap();
+        assert_eq!(config.mcu.as_deref(), Some("TEENSY31"));
+        assert_eq!(config.file.as_deref(), Some("blink.hex"));
+        assert_eq!(config.timeout, Some(5000));
+    }
+
+    #[test]
+    fn cargo_metadata() {
+        let manifest =
+            "[package]\nname = \"blink\"\n\n[package.metadata.teensy]\nmcu = \"TEENSY40\"\n";
+        let dir = TempDir::new("cargo_metadata", &[(CARGO_MANIFEST, manifest)]);
+        assert_eq!(
+            load_config(&dir.0).unwrap().mcu.as_deref(),
+            Some("TEENSY40")
+        );
+
+        // A manifest without the table is no config at all
+        let dir = TempDir::new("cargo_no_metadata", &[(CARGO_MANIFEST, "[package]\n")]);
+        assert_eq!(load_config(&dir.0).unwrap().mcu, None);
+
+        // .teensy.toml wins, the manifest isn't even parsed
+        let dir = TempDir::new(
+            "both",
+            &[
+                (CONFIG_FILE, "mcu = \"TEENSY31\"\n"),
+                (CARGO_MANIFEST, "not toml"),
+            ],
+        );
+        assert_eq!(
+            load_config(&dir.0).unwrap().mcu.as_deref(),
+            Some("TEENSY31")
+        );
+    }
+
+    #[test]
+    fn invalid_toml() {
+        let dir = TempDir::new("invalid", &[(CONFIG_FILE, "mcu = \n")]);
+        assert!(matches!(
+            load_config(&dir.0),
+            Err(ConfigError::Parse(CONFIG_FILE, _))
+        ));
+
+        let dir = TempDir::new("invalid_manifest", &[(CARGO_MANIFEST, "[package\n")]);
+        assert!(matches!(
+            load_config(&dir.0),
+            Err(ConfigError::Parse(CARGO_MANIFEST, _))
+        ));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let dir = TempDir::new(
+            "unknown",
+            &[(CONFIG_FILE, "mcu = \"TEENSY31\"\nport = 3\n")],
+        );
+        assert!(matches!(
+            load_config(&dir.0),
+            Err(ConfigError::Parse(CONFIG_FILE, _))
+        ));
+
+        // Also inside the manifest's table, the rest of the manifest is cargo's business
+        let manifest = "[package]\nname = \"blink\"\n\n[package.metadata.teensy]\nport = 3\n";
+        let dir = TempDir::new("unknown_manifest", &[(CARGO_MANIFEST, manifest)]);
+        assert!(matches!(
+            load_config(&dir.0),
+            Err(ConfigError::Parse(CARGO_MANIFEST, _))
+        ));
+    }
+
+    #[test]
+    fn arguments_then_environment_then_config() {
+        let config = Config {
+            mcu: Some("TEENSY31".to_string()),
+            file: Some("config.hex".to_string()),
+            timeout: None,
+        };
+        assert_eq!(
+            config.resolve_mcu(Some("TEENSY40"), Some("TEENSYLC")),
+            Some("TEENSY40")
+        );
+        assert_eq!(config.resolve_mcu(None, Some("TEENSYLC")), Some("TEENSYLC"));
+        assert_eq!(config.resolve_mcu(None, None), Some("TEENSY31"));
+        assert_eq!(
+            config.resolve_file(Some("arg.hex"), Some("env.hex")),
+            Some("arg.hex")
+        );
+        assert_eq!(config.resolve_file(None, Some("env.hex")), Some("env.hex"));
+        assert_eq!(config.resolve_file(None, None), Some("config.hex"));
+
+        assert_eq!(Config::default().resolve_mcu(None, None), None);
+        assert_eq!(Config::default().resolve_file(None, None), None);
+    }
+}
@@ -1,17 +1,17 @@
 use std::fs::File;
-use std::io::{Error as IoError, Read};
+use std::io::{Error as IoError, Read, Write};
+use std::ops::{Range, RangeInclusive};
 
 use elf_rs::{
-    Elf, Elf32, ElfAbi, ElfMachine, ElfType, GenElf, GenElfHeader, GenProgramHeader,
-    GenSectionHeader, ProgramHeader32, ProgramType, SectionHeader, SectionHeader32,
-    SectionHeaderFlags, SectionType,
+    Elf, Elf32, ElfAbi, ElfEndian, ElfMachine, ElfType, GenElf, GenElfHeader, GenSectionHeader,
+    ProgramType, SectionHeader, SectionHeader32, SectionHeaderFlags, SectionType,
 };
 use ihex::reader::Reader as IHexReader;
 use ihex::record::Record as IHexRecord;
 
 pub mod usb;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Mcu {
     pub code_size: usize,
     pub block_size: usize,
@@ -98,17 +98,179 @@ static ALIASES: [(&'static str, &'static str); 8] = [
 
 // FIXME:
 pub fn parse_mcu(arg: &str) -> Option<Mcu> {
-    let name = ALIASES
+    mcu_by_name(arg)
+}
+
+/// `parse_mcu` for names known at compile time.
+///
+/// Being a `const fn`, this can initialise a `const`, so a board fixed at build time costs no
+/// lookup at runtime. See `mcu!` for a version that turns an unknown name into a compile error.
+pub const fn mcu_by_name(name: &str) -> Option<Mcu> {
+    let mut name = name;
+    let mut i = 0;
+    while i < ALIASES.len() {
+        if str_eq(ALIASES[i].0, name) {
+            name = ALIASES[i].1;
+            break;
+        }
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < MCUS.len() {
+        if str_eq(MCUS[i].0, name) {
+            return Some(MCUS[i].1);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// `==` for strings, which isn't available in const contexts.
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Looks up an MCU or board name at compile time, failing the build if it isn't supported.
+///
+/// ```
+/// const BOARD: rusty_loader::Mcu = rusty_loader::mcu!("TEENSY31");
+/// assert_eq!(BOARD.code_size, 262144);
+/// ```
+#[macro_export]
+macro_rules! mcu {
+    ($name:expr) => {{
+        const MCU: $crate::Mcu = match $crate::mcu_by_name($name) {
+            Some(mcu) => mcu,
+            None => panic!("unsupported MCU"),
+        };
+        MCU
+    }};
+}
+
+/// Board names that stand for the MCU called `mcu_name`, in the order of `alias_table`.
+///
+/// This is the inverse of `chip_for_alias`. Empty if no board uses the MCU, or if `mcu_name`
+/// isn't an MCU name at all.
+pub fn aliases_for(mcu_name: &str) -> Vec<&'static str> {
+    ALIASES
+        .iter()
+        .filter(|&&(_, name)| name == mcu_name)
+        .map(|&(alias, _)| alias)
+        .collect()
+}
+
+/// Name of the MCU the board name `alias` stands for, `None` if it isn't a board name.
+pub fn chip_for_alias(alias: &str) -> Option<&'static str> {
+    ALIASES
         .iter()
-        .filter(|&&(alias, _)| alias == arg)
-        .next()
-        .map(|&(_, n)| n)
-        .unwrap_or(arg);
+        .find(|&&(a, _)| a == alias)
+        .map(|&(_, name)| name)
+}
 
+/// Board names (the aliases in `supported_mcus`) that use `mcu`.
+///
+/// Several boards can share a chip, e.g. both TEENSY31 and TEENSY32 are mk20dx256, so all of them
+/// are returned.
+pub fn mcu_to_board_names(mcu: &Mcu) -> Vec<&'static str> {
     MCUS.iter()
-        .filter(|(n, ..)| *n == name)
-        .next()
-        .map(|&(_, mcu)| mcu)
+        .filter(|(_, m)| m == mcu)
+        .flat_map(|&(name, _)| aliases_for(name))
+        .collect()
+}
+
+/// Whether `mcu` is one of the Kinetis (Cortex-M) parts rather than an AVR.
+fn is_cortex_m(mcu: &Mcu) -> bool {
+    MCUS.iter()
+        .any(|(name, m)| m == mcu && name.starts_with("mk"))
+}
+
+/// Suggests an MCU with at least `size` bytes of flash for an image that doesn't fit on `mcu`.
+///
+/// Only parts of the same architecture as `mcu` are considered, and the smallest one that fits is
+/// picked. Returns its first board name, or the MCU name if no board uses it, along with it.
+pub fn smallest_mcu_fitting(mcu: &Mcu, size: usize) -> Option<(&'static str, Mcu)> {
+    let cortex_m = is_cortex_m(mcu);
+    MCUS.iter()
+        .filter(|&(_, m)| is_cortex_m(m) == cortex_m && m.code_size >= size)
+        .min_by_key(|&(_, m)| m.code_size)
+        .map(|&(name, m)| {
+            let board = mcu_to_board_names(&m).first().copied();
+            (board.unwrap_or(name), m)
+        })
+}
+
+/// Guesses the MCU for an ELF image ending at `image_max_addr`: the smallest part with room for it.
+///
+/// Only ARM ELFs can be loaded, so only the Cortex-M parts are considered. The image may well have
+/// been built for a bigger part, so treat the answer as a guess.
+pub fn infer_mcu_from_image(image_max_addr: usize) -> Option<&'static str> {
+    MCUS.iter()
+        .filter(|&(_, m)| is_cortex_m(m) && m.code_size >= image_max_addr)
+        .min_by_key(|&(_, m)| m.code_size)
+        .map(|&(name, _)| name)
+}
+
+/// The MCU `infer_mcu_from_file` worked out, and how sure it is of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InferredMcu {
+    /// The ELF's target MCU note names it.
+    Named(&'static str),
+    /// The ELF doesn't say, this is what `infer_mcu_from_image` picked for its size.
+    Guessed(&'static str),
+}
+
+/// Version of this crate, for front ends to display.
+pub fn crate_version() -> &'static str {
+    option_env!("CARGO_PKG_VERSION").unwrap_or("unknown")
+}
+
+/// What can be done with a part through its bootloader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities {
+    /// Flash can be read back, see `usb::Teensy::dump`.
+    pub supports_read: bool,
+    /// Flash can be compared with an image after programming.
+    pub supports_verify: bool,
+    /// EEPROM can be written.
+    pub supports_eeprom: bool,
+    /// Flash can be erased without programming anything, see `usb::Teensy::erase`.
+    pub supports_erase: bool,
+}
+
+/// What the bootloader on `mcu` can do, for front ends deciding which actions to offer.
+///
+/// HalfKay is write only on every part, AVR and Kinetis alike: it takes flash blocks and the
+/// boot command, and erases the chip when block 0 arrives. So only erasing is supported, and
+/// reading, verifying and EEPROM are not.
+pub fn capabilities(_mcu: &Mcu) -> Capabilities {
+    Capabilities {
+        supports_read: false,
+        supports_verify: false,
+        supports_eeprom: false,
+        supports_erase: true,
+    }
+}
+
+/// Every supported MCU by name, in order of architecture and then flash size.
+pub fn mcu_table() -> &'static [(&'static str, Mcu)] {
+    &MCUS
+}
+
+/// Every board name and the MCU name it stands for.
+pub fn alias_table() -> &'static [(&'static str, &'static str)] {
+    &ALIASES
 }
 
 pub fn supported_mcus() -> Vec<&'static str> {
@@ -118,6 +280,42 @@ pub fn supported_mcus() -> Vec<&'static str> {
         .collect()
 }
 
+/// Lowest and highest possible initial stack pointer on the supported Cortex-M parts. Kinetis SRAM
+/// straddles 0x2000_0000, reaching furthest on the mk64fx512 and mk66fx1m0, and the stack usually
+/// starts at its very end.
+const CORTEX_M_STACK: RangeInclusive<u32> = 0x1FFF_0000..=0x2003_0000;
+
+/// Ways `check_vector_table` can find the start of an image to be broken.
+#[derive(Debug, PartialEq)]
+pub enum VectorTableProblem {
+    /// The initial stack pointer isn't a word aligned address in RAM.
+    BadStackPointer(u32),
+    /// The reset vector isn't a Thumb address in flash.
+    BadResetVector(u32),
+}
+
+/// Sanity checks the initial stack pointer and reset vector at the start of a Cortex-M image.
+///
+/// A zeroed or out of range vector almost always comes from a broken linker script and leaves the
+/// board hanging at reset. Returns `None` if they look plausible, and always for the AVR parts,
+/// which start executing at 0 instead of reading a vector table.
+pub fn check_vector_table(image: &[u8], mcu: &Mcu) -> Option<VectorTableProblem> {
+    if !is_cortex_m(mcu) || image.len() < 8 {
+        return None;
+    }
+
+    let word =
+        |at: usize| u32::from_le_bytes([image[at], image[at + 1], image[at + 2], image[at + 3]]);
+    let (sp, pc) = (word(0), word(4));
+    if !CORTEX_M_STACK.contains(&sp) || sp % 4 != 0 {
+        Some(VectorTableProblem::BadStackPointer(sp))
+    } else if pc & 1 == 0 || (pc & !1) as usize >= mcu.code_size {
+        Some(VectorTableProblem::BadResetVector(pc))
+    } else {
+        None
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum FileHint {
     IHEX,
@@ -135,112 +333,547 @@ impl FileHint {
     }
 }
 
+/// Records which bytes of a flash image came from the input file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TouchedMap {
+    touched: Vec<bool>,
+}
+
+impl TouchedMap {
+    /// An empty map for an image of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        TouchedMap {
+            touched: vec![false; size],
+        }
+    }
+
+    pub fn mark(&mut self, range: Range<usize>) {
+        for t in &mut self.touched[range] {
+            *t = true;
+        }
+    }
+
+    pub fn is_touched(&self, addr: usize) -> bool {
+        self.touched.get(addr).copied().unwrap_or(false)
+    }
+
+    /// Number of bytes the file provided.
+    pub fn written(&self) -> usize {
+        self.touched.iter().filter(|&&t| t).count()
+    }
+
+    /// The range from the first to the last touched byte, including any gaps.
+    pub fn span(&self) -> Option<Range<usize>> {
+        let start = self.touched.iter().position(|&t| t)?;
+        let end = self.touched.iter().rposition(|&t| t)? + 1;
+        Some(start..end)
+    }
+}
+
+/// Percentage of `mcu`'s flash occupied by the image.
+///
+/// This counts the whole span from the first to the last touched byte, as gaps in a sparse image
+/// can't be used by anything else either.
+pub fn usage(touched: &TouchedMap, mcu: &Mcu) -> f64 {
+    let span = touched.span().map_or(0, |span| span.len());
+    span as f64 / mcu.code_size as f64 * 100.0
+}
+
+/// Where two flash images differ, as ranges of whole `block_size` blocks, since that's what
+/// gets programmed. Neighbouring blocks that differ are merged into one range.
+///
+/// Past the end of the shorter image every block counts as different.
+pub fn diff_images(a: &[u8], b: &[u8], block_size: usize) -> Vec<Range<usize>> {
+    fn block(image: &[u8], start: usize, end: usize) -> Option<&[u8]> {
+        image
+            .get(start..end.min(image.len()))
+            .filter(|b| !b.is_empty())
+    }
+
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for start in (0..a.len().max(b.len())).step_by(block_size) {
+        let end = start + block_size;
+        if block(a, start, end) == block(b, start, end) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
 #[derive(Debug)]
 pub enum LoadError {
     FailedOpen(IoError),
     FailedRead(IoError),
     NotValidFile,
+    /// The file is a position independent (`ET_DYN`) ELF and no `LoadOptions::base` was given.
+    NeedsBase,
+    /// The file is an ELF, but not one that can be flashed.
+    InvalidElf(ElfError),
+    /// The file is Intel hex, but not one that can be flashed.
+    InvalidIHex(IHexError),
+}
+
+/// Options controlling how `load_file_with` turns a file into an image.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /// Address a position independent (`ET_DYN`) ELF is loaded at.
+    ///
+    /// Such ELFs are rejected without one. It has no effect on other files.
+    pub base: Option<u32>,
+    /// Value of every byte of the image the file doesn't provide, 0xFF if not set.
+    ///
+    /// 0xFF is what erased flash reads as on every supported part. Only blocks of 0xFF are left
+    /// out when programming, so with any other value the whole of flash is written.
+    pub fill_byte: Option<u8>,
+}
+
+/// The byte unused flash is filled with unless told otherwise, as erased flash reads.
+pub const DEFAULT_FILL_BYTE: u8 = 0xFF;
+
+/// A file format that can be turned into a flash image.
+///
+/// `parse_image` tries the built-in formats, `BUILTIN_FORMATS`. Other formats can be read by
+/// implementing this and passing them to `parse_image_as`.
+pub trait FileFormat {
+    /// Name of the format, for messages.
+    fn name(&self) -> &'static str;
+
+    /// The hint naming this format, `FileHint::Any` for a format without one.
+    fn hint(&self) -> FileHint;
+
+    /// Whether `data` looks like this format from its first bytes, without parsing it.
+    fn detect(&self, data: &[u8]) -> bool;
+
+    /// Reads `data` into a flat image of `mcu.code_size` bytes, returning the image and which
+    /// bytes of it the file provided.
+    ///
+    /// Fails with `LoadError::NotValidFile` if `data` isn't this format at all, so the next
+    /// format can be tried, and with another error if it is but can't be flashed.
+    fn to_image(
+        &self,
+        data: &[u8],
+        mcu: &Mcu,
+        options: &LoadOptions,
+    ) -> Result<(Vec<u8>, TouchedMap), LoadError>;
 }
 
+/// 32-bit little-endian ARM ELF files, executables or, given a base address, PIEs.
+pub struct ElfFormat;
+
+impl FileFormat for ElfFormat {
+    fn name(&self) -> &'static str {
+        FileHint::ELF.to_str()
+    }
+
+    fn hint(&self) -> FileHint {
+        FileHint::ELF
+    }
+
+    fn detect(&self, data: &[u8]) -> bool {
+        data.starts_with(b"\x7FELF")
+    }
+
+    fn to_image(
+        &self,
+        data: &[u8],
+        mcu: &Mcu,
+        options: &LoadOptions,
+    ) -> Result<(Vec<u8>, TouchedMap), LoadError> {
+        let data = AlignedBytes::new(data);
+        let elf = match Elf::from_bytes(data.as_bytes()) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => return Err(LoadError::NotValidFile),
+        };
+
+        // Checked first, every other header field is read as little-endian
+        if elf.header().endianness() != ElfEndian::LittleEndian {
+            return Err(LoadError::InvalidElf(ElfError::WrongEndianness));
+        }
+        if elf.header().machine() != ElfMachine::ARM
+            // SystemV is used as None
+            || elf.header().abi() != ElfAbi::SystemV
+            || program_headers(&elf).iter().any(|phdr| {
+                phdr.ph_type == ProgramType::INTERP
+                    // PIEs always carry their dynamic section, it doesn't need loading
+                    || (phdr.ph_type == ProgramType::DYNAMIC
+                        && elf.header().elftype() != ElfType::ET_DYN)
+            })
+        {
+            return Err(LoadError::NotValidFile);
+        }
+
+        let base = match (elf.header().elftype(), options.base) {
+            (ElfType::ET_EXEC, _) => 0,
+            (ElfType::ET_DYN, Some(base)) => base,
+            (ElfType::ET_DYN, None) => return Err(LoadError::NeedsBase),
+            _ => return Err(LoadError::NotValidFile),
+        };
+        let options = LoadOptions {
+            base: Some(base),
+            ..options.clone()
+        };
+        elf32_to_bytes_with(&elf, mcu, &options).map_err(LoadError::InvalidElf)
+    }
+}
+
+/// Intel hex files.
+pub struct IHexFormat;
+
+impl FileFormat for IHexFormat {
+    fn name(&self) -> &'static str {
+        FileHint::IHEX.to_str()
+    }
+
+    fn hint(&self) -> FileHint {
+        FileHint::IHEX
+    }
+
+    /// Intel hex files start with a `:` record mark once any leading whitespace is skipped.
+    fn detect(&self, data: &[u8]) -> bool {
+        data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b':')
+    }
+
+    fn to_image(
+        &self,
+        data: &[u8],
+        mcu: &Mcu,
+        options: &LoadOptions,
+    ) -> Result<(Vec<u8>, TouchedMap), LoadError> {
+        let file_str = String::from_utf8_lossy(data);
+        let recs: Vec<_> = IHexReader::new(&file_str)
+            .collect::<Result<_, _>>()
+            .map_err(|_| LoadError::NotValidFile)?;
+        ihex_to_bytes_with(&recs, mcu, options).map_err(LoadError::InvalidIHex)
+    }
+}
+
+/// The formats `parse_image` reads, in the order they are tried.
+pub const BUILTIN_FORMATS: &[&dyn FileFormat] = &[&ElfFormat, &IHexFormat];
+
+/// Guesses the format of a file from its first bytes, without parsing it.
+///
+/// Returns `None` if the contents look like none of `BUILTIN_FORMATS`.
+pub fn detect_format(data: &[u8]) -> Option<FileHint> {
+    BUILTIN_FORMATS
+        .iter()
+        .find(|format| format.detect(data))
+        .map(|format| format.hint())
+}
+
+/// Loads `file_path` as a flat image of `mcu.code_size` bytes.
+///
+/// Returns the image, which bytes of it the file provided, and the format the file was read as.
+/// The latter is only interesting when `hint` is `FileHint::Any`.
 pub fn load_file(
     file_path: &str,
     hint: FileHint,
     mcu: &Mcu,
-) -> Result<(Vec<u8>, usize), LoadError> {
+) -> Result<(Vec<u8>, TouchedMap, FileHint), LoadError> {
+    load_file_with(file_path, hint, mcu, &LoadOptions::default())
+}
+
+/// Writes out what the parsers see in `data`, one line per record or header, for working out why
+/// a file won't load.
+///
+/// A 32-bit ELF has its program headers and then its sections listed, unless `hint` is
+/// `FileHint::IHEX`. Anything else is read as Intel hex, listing records up to the first one that
+/// fails to parse, and then the error.
+pub fn dump_records(data: &[u8], hint: FileHint, out: &mut impl Write) -> std::io::Result<()> {
+    if hint != FileHint::IHEX {
+        let aligned = AlignedBytes::new(data);
+        if let Ok(Elf::Elf32(elf)) = Elf::from_bytes(aligned.as_bytes()) {
+            for phdr in program_headers(&elf) {
+                writeln!(
+                    out,
+                    "{:?} offset {:#x} vaddr {:#010x} paddr {:#010x} filesz {:#x} memsz {:#x} flags {:#x}",
+                    phdr.ph_type,
+                    phdr.offset,
+                    phdr.vaddr,
+                    phdr.paddr,
+                    phdr.filesz,
+                    phdr.memsz,
+                    phdr.flags
+                )?;
+            }
+            for section in elf.section_header_iter() {
+                writeln!(
+                    out,
+                    "section \"{}\" {:?} {:?} addr {:#010x} size {:#x}",
+                    section_name(&section),
+                    section.sh.sh_type(),
+                    section.sh.flags(),
+                    section.sh.addr(),
+                    section.sh.size()
+                )?;
+            }
+            return Ok(());
+        }
+    }
+    if hint == FileHint::ELF {
+        return writeln!(out, "not a 32-bit ELF file");
+    }
+
+    let file_str = String::from_utf8_lossy(data);
+    for rec in IHexReader::new(&file_str) {
+        match rec {
+            Ok(IHexRecord::Data { offset, value }) => {
+                writeln!(out, "Data offset {:#06x} len {}", offset, value.len())?
+            }
+            Ok(IHexRecord::ExtendedSegmentAddress(addr)) => {
+                writeln!(out, "ExtendedSegmentAddress {:#06x}", addr)?
+            }
+            Ok(IHexRecord::ExtendedLinearAddress(addr)) => {
+                writeln!(out, "ExtendedLinearAddress {:#06x}", addr)?
+            }
+            Ok(IHexRecord::StartSegmentAddress { cs, ip }) => {
+                writeln!(out, "StartSegmentAddress {:#06x}:{:#06x}", cs, ip)?
+            }
+            Ok(IHexRecord::StartLinearAddress(addr)) => {
+                writeln!(out, "StartLinearAddress {:#010x}", addr)?
+            }
+            Ok(IHexRecord::EndOfFile) => writeln!(out, "EndOfFile")?,
+            Err(err) => return writeln!(out, "error: {}", err),
+        }
+    }
+    Ok(())
+}
+
+/// Like `load_file`, but with control over how the file is interpreted.
+pub fn load_file_with(
+    file_path: &str,
+    hint: FileHint,
+    mcu: &Mcu,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, TouchedMap, FileHint), LoadError> {
     let mut file = File::open(file_path).map_err(|e| LoadError::FailedOpen(e))?;
     let mut file_buf = Vec::new();
     file.read_to_end(&mut file_buf)
         .map_err(|e| LoadError::FailedRead(e))?;
+    parse_image(&file_buf, hint, mcu, options)
+}
 
-    // Assume the file is an ELF file first. If that fails to parse, try IHEX.
-    if hint != FileHint::IHEX {
-        match Elf::from_bytes(&file_buf[..]) {
-            // TODO: Return errors
-            Ok(Elf::Elf32(elf)) => {
-                if elf.header().machine() != ElfMachine::ARM {
-                    None
-                } else if elf.header().abi() != ElfAbi::SystemV {
-                    // SystemV is used as None
-                    None
-                } else if elf.header().elftype() != ElfType::ET_EXEC {
-                    None
-                } else if elf.program_headers().iter().any(|phdr| {
-                    phdr.ph_type() == ProgramType::DYNAMIC || phdr.ph_type() == ProgramType::INTERP
-                }) {
-                    None
-                } else {
-                    elf32_to_bytes(&elf, mcu).ok()
-                    //eprintln!("Failed to parse \"{}\" into binary form", file_path);
-                    //println_verbose!("Error: {:?}", err);
-                }
+/// Like `load_file_with`, but for the contents of a file already in memory.
+///
+/// `file_buf` needn't be aligned, ELFs are copied to an aligned buffer before they are parsed.
+/// Callers handing a buffer to elf_rs themselves, for `elf32_to_bytes` and friends, have to make
+/// sure it is 8 byte aligned.
+pub fn parse_image(
+    file_buf: &[u8],
+    hint: FileHint,
+    mcu: &Mcu,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, TouchedMap, FileHint), LoadError> {
+    // An explicit hint is authoritative
+    let formats: &[&dyn FileFormat] = match hint {
+        FileHint::ELF => &[&ElfFormat],
+        FileHint::IHEX => &[&IHexFormat],
+        FileHint::Any => BUILTIN_FORMATS,
+    };
+    parse_image_as(file_buf, formats, mcu, options)
+        .map(|(bin, touched, format)| (bin, touched, format.hint()))
+}
+
+/// Like `parse_image`, but reading `file_buf` as one of `formats`, tried in order.
+///
+/// Only the first format the contents look like is tried, or all of them if they look like none.
+/// Returns the image, which bytes of it the file provided, and the format it was read as. If no
+/// format can read it, fails with the first error other than `LoadError::NotValidFile`, as that
+/// is the format the file most likely is.
+pub fn parse_image_as<'f>(
+    file_buf: &[u8],
+    formats: &'f [&'f dyn FileFormat],
+    mcu: &Mcu,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, TouchedMap, &'f dyn FileFormat), LoadError> {
+    let formats = match formats.iter().find(|format| format.detect(file_buf)) {
+        Some(format) => std::slice::from_ref(format),
+        None => formats,
+    };
+
+    let mut error = None;
+    for &format in formats {
+        match format.to_image(file_buf, mcu, options) {
+            Ok((bin, touched)) => return Ok((bin, touched, format)),
+            Err(LoadError::NotValidFile) => {}
+            Err(err) => {
+                error.get_or_insert(err);
             }
-            _ => None,
         }
-    } else {
-        None
     }
-    .or_else(|| {
-        if hint != FileHint::ELF {
-            let file_str = String::from_utf8_lossy(&file_buf[..]);
-            let ihex_reader = IHexReader::new(&file_str);
-            let ihex_records: Result<Vec<_>, _> = ihex_reader.collect();
-            match ihex_records {
-                Ok(r) => Some(r),
-                Err(_err) => {
-                    //eprintln!("Failed to parse \"{}\" as Intel hex", file_path);
-                    //println_verbose!("Error: {}", err);
-                    None
-                }
+    Err(error.unwrap_or(LoadError::NotValidFile))
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// `crc32` worked out a piece at a time, for data that is never all in memory at once.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                self.0 = if self.0 & 1 != 0 {
+                    (self.0 >> 1) ^ 0xEDB8_8320
+                } else {
+                    self.0 >> 1
+                };
             }
-            .and_then(|ihex_records| {
-                match ihex_to_bytes(&ihex_records, mcu) {
-                    Err(_err) => {
-                        //eprintln!("Failed to parse \"{}\" into binary form", file_path);
-                        //println_verbose!("Error: {:?}", err);
-                        None
-                    }
-                    Ok(bin) => Some(bin),
-                }
-            })
-        } else {
-            None
         }
-    })
-    .ok_or(LoadError::NotValidFile)
+    }
+
+    /// The CRC of everything passed to `update` so far.
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum IHexError {
+    /// Data runs past the end of flash. Holds the end of the highest addressed data in the file,
+    /// so the image needs at least that many bytes of flash.
     AddressTooHigh(usize),
 }
 
-pub fn ihex_to_bytes(recs: &[IHexRecord], mcu: &Mcu) -> Result<(Vec<u8>, usize), IHexError> {
-    let mut base_address = 0;
-    let mut bytes = vec![0xFF; mcu.code_size];
-    let mut len = 0;
+/// How the base address of data records was last set.
+#[derive(Clone, Copy)]
+enum IHexBase {
+    /// `ExtendedSegmentAddress`: offsets wrap within the 64 KiB segment and the resulting address
+    /// wraps at 1 MiB.
+    Segment(usize),
+    /// `ExtendedLinearAddress`: offsets are added linearly.
+    Linear(usize),
+}
+
+impl IHexBase {
+    fn address(self, offset: usize) -> usize {
+        match self {
+            IHexBase::Segment(base) => (base + (offset & 0xFFFF)) & 0xF_FFFF,
+            IHexBase::Linear(base) => base + offset,
+        }
+    }
+}
+
+pub fn ihex_to_bytes(recs: &[IHexRecord], mcu: &Mcu) -> Result<(Vec<u8>, TouchedMap), IHexError> {
+    ihex_to_bytes_with(recs, mcu, &LoadOptions::default())
+}
+
+/// Like `ihex_to_bytes`, but filling the gaps with `options.fill_byte`.
+pub fn ihex_to_bytes_with(
+    recs: &[IHexRecord],
+    mcu: &Mcu,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, TouchedMap), IHexError> {
+    let mut base = IHexBase::Linear(0);
+    let fill = options.fill_byte.unwrap_or(DEFAULT_FILL_BYTE);
+    let mut bytes = vec![fill; mcu.code_size];
+    let mut touched = TouchedMap::new(mcu.code_size);
+    // Once something doesn't fit, the rest is only read to find how far the image reaches
+    let mut too_high = None;
 
     for rec in recs {
         match rec {
             IHexRecord::Data { offset, value } => {
-                let end_addr = base_address + *offset as usize + value.len();
-                if end_addr >= mcu.code_size {
-                    return Err(IHexError::AddressTooHigh(end_addr));
+                let addrs = (0..value.len()).map(|n| base.address(*offset as usize + n));
+                let end_addr = addrs.clone().max().map_or(0, |a| a + 1);
+                if end_addr > mcu.code_size || too_high.is_some() {
+                    too_high = too_high.max(Some(end_addr));
+                    continue;
                 }
 
-                len += value.len();
-                for (n, b) in value.iter().enumerate() {
-                    bytes[base_address + *offset as usize + n] = *b;
+                for (addr, b) in addrs.zip(value) {
+                    bytes[addr] = *b;
+                    touched.mark(addr..addr + 1);
                 }
             }
-            IHexRecord::ExtendedSegmentAddress(base) => base_address = (*base as usize) << 4,
-            IHexRecord::ExtendedLinearAddress(base) => base_address = (*base as usize) << 16,
+            IHexRecord::ExtendedSegmentAddress(addr) => {
+                base = IHexBase::Segment((*addr as usize) << 4)
+            }
+            IHexRecord::ExtendedLinearAddress(addr) => {
+                base = IHexBase::Linear((*addr as usize) << 16)
+            }
             IHexRecord::EndOfFile => break,
             // Defines the start location for our program. This doesn't concern us so we ignore it.
             IHexRecord::StartLinearAddress(_) | IHexRecord::StartSegmentAddress { .. } => {}
         }
     }
 
-    Ok((bytes, len))
+    if let Some(end_addr) = too_high {
+        return Err(IHexError::AddressTooHigh(end_addr));
+    }
+
+    Ok((bytes, touched))
+}
+
+/// Number of data bytes placed in each record by `bytes_to_ihex`, as most tools emit.
+pub const DEFAULT_IHEX_RECORD_LEN: usize = 16;
+
+/// Encodes a flat image starting at `base` as Intel hex records.
+///
+/// The image is split into `Data` records that never cross a 64 KiB boundary, with an
+/// `ExtendedLinearAddress` record emitted whenever the upper 16 bits of the address change.
+/// The records are terminated with `EndOfFile`. This is the inverse of `ihex_to_bytes`.
+pub fn bytes_to_ihex(data: &[u8], base: u32) -> Vec<IHexRecord> {
+    bytes_to_ihex_with(data, base, DEFAULT_IHEX_RECORD_LEN)
+}
+
+/// Like `bytes_to_ihex`, but with up to `record_len` data bytes in each `Data` record, for tools
+/// that expect a width other than 16.
+///
+/// # Panics
+///
+/// If `record_len` is 0 or doesn't fit the record's one byte length field, i.e. is over 255.
+pub fn bytes_to_ihex_with(data: &[u8], base: u32, record_len: usize) -> Vec<IHexRecord> {
+    assert!(
+        (1..=255).contains(&record_len),
+        "Intel hex records hold 1 to 255 data bytes, not {}",
+        record_len
+    );
+
+    let mut recs = Vec::new();
+    let mut upper = 0;
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let addr = base as usize + pos;
+        let addr_upper = (addr >> 16) as u16;
+        if addr_upper != upper {
+            recs.push(IHexRecord::ExtendedLinearAddress(addr_upper));
+            upper = addr_upper;
+        }
+
+        let len = record_len
+            .min(0x10000 - (addr & 0xFFFF))
+            .min(data.len() - pos);
+        recs.push(IHexRecord::Data {
+            offset: addr as u16,
+            value: data[pos..pos + len].to_vec(),
+        });
+        pos += len;
+    }
+    recs.push(IHexRecord::EndOfFile);
+
+    recs
 }
 
 struct Section<'a> {
@@ -249,64 +882,351 @@ struct Section<'a> {
     size: u32,
 }
 
-impl<'a, 'b> Section<'a> {
-    fn new(sec: SectionHeader<'a, Elf32<'a>>, phdrs: &'b [ProgramHeader32]) -> Self {
+impl<'a> Section<'a> {
+    /// Places `sec` at its physical address in `phdr`, the segment loading it. `base` is added to
+    /// the load address, for relocating PIEs.
+    ///
+    /// A load address past the end of the address space can't be in flash either, so that is
+    /// `ElfError::SectionOutsideFlash` rather than wrapping around.
+    fn new(
+        sec: SectionHeader<'a, Elf32<'a>>,
+        phdr: &ProgramHeader,
+        base: u32,
+    ) -> Result<Self, ElfError> {
         let shdr = sec.sh;
+        // `phdr_for_section` only hands out segments containing the section
+        let addr = shdr.addr() - phdr.vaddr;
+        let load_addr = addr
+            .checked_add(phdr.paddr)
+            .and_then(|addr| addr.checked_add(base))
+            .ok_or_else(|| ElfError::SectionOutsideFlash {
+                name: section_name(&sec),
+                addr: shdr.addr(),
+            })?;
+        Ok(Section {
+            shdr: sec,
+            load_addr,
+            size: shdr.size(),
+        })
+    }
+}
 
-        if let Some(phdr) = phdr_for_section(shdr, phdrs) {
-            Section {
-                shdr: sec,
-                load_addr: shdr.addr() - phdr.vaddr() + phdr.paddr(),
-                size: shdr.size(),
-            }
-        } else {
-            Section {
-                shdr: sec,
-                load_addr: shdr.addr(),
-                size: shdr.size(),
-            }
+/// Name of `sec` as a `String`.
+///
+/// elf_rs only exposes section names as a `CChar`, which has neither `Display` nor a way to get at
+/// its bytes, just a `Debug` that writes them out unquoted.
+fn section_name(sec: &SectionHeader<'_, Elf32<'_>>) -> String {
+    format!("{:?}", sec.section_name())
+}
+
+/// A copy of some bytes, aligned for any ELF header.
+///
+/// elf_rs reads headers by casting pointers into the buffer it is given, which is only sound when
+/// the buffer is aligned for them. Neither `&[u8]` nor `Vec<u8>` promise any alignment, data from
+/// `include_bytes!` in particular often isn't, so ELFs are copied into one of these before parsing.
+struct AlignedBytes {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    fn new(data: &[u8]) -> Self {
+        let mut words = vec![0u64; data.len().div_ceil(8)];
+        for (word, chunk) in words.iter_mut().zip(data.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u64::from_ne_bytes(bytes);
+        }
+        AlignedBytes {
+            words,
+            len: data.len(),
         }
     }
+
+    fn as_bytes(&self) -> &[u8] {
+        // SAFETY: `words` holds at least `len` initialised bytes, and `u8` has no alignment
+        // requirement
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+}
+
+/// Reads the little-endian `u32` at `at` in `buf`, `None` if it runs past the end.
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    let bytes = buf.get(at..at.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A 32-bit ELF program header.
+///
+/// elf_rs 0.1's `ProgramHeader32` has its fields in the 64-bit order, with `p_flags` second, so
+/// everything after the type reads the wrong field. `program_headers` reads them itself instead.
+#[derive(Clone, Copy, Debug)]
+struct ProgramHeader {
+    ph_type: ProgramType,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+}
+
+/// The program headers of `elf`, leaving out any that run past the end of the file.
+fn program_headers(elf: &Elf32) -> Vec<ProgramHeader> {
+    let buf = elf.as_bytes();
+    let header = elf.header();
+    let offset = header.program_header_offset() as usize;
+    let entry_size = header.program_header_entry_size() as usize;
+    (0..header.program_header_entry_num() as usize)
+        .filter_map(|i| {
+            let word = |n: usize| read_u32(buf, offset + i * entry_size + n * 4);
+            Some(ProgramHeader {
+                ph_type: word(0)?.into(),
+                offset: word(1)?,
+                vaddr: word(2)?,
+                paddr: word(3)?,
+                filesz: word(4)?,
+                memsz: word(5)?,
+                flags: word(6)?,
+            })
+        })
+        .collect()
 }
 
-fn phdr_for_section<'a, 'b>(
-    shdr: &'a SectionHeader32,
-    phdrs: &'b [ProgramHeader32],
-) -> Option<&'b ProgramHeader32> {
+fn phdr_for_section<'a>(
+    shdr: &SectionHeader32,
+    phdrs: &'a [ProgramHeader],
+) -> Option<&'a ProgramHeader> {
     phdrs.iter().find(|phdr| {
-        shdr.addr() >= phdr.vaddr() && shdr.addr() + shdr.size() <= phdr.vaddr() + phdr.memsz()
+        shdr.addr() >= phdr.vaddr && shdr.addr() + shdr.size() <= phdr.vaddr + phdr.memsz
     })
 }
 
 #[derive(Debug, PartialEq)]
-pub enum ElfError {}
-
-// TODO: verify nothing is above the MCU's code size
-pub fn elf32_to_bytes(elf: &Elf32, mcu: &Mcu) -> Result<(Vec<u8>, usize), ElfError> {
-    let sections: Vec<_> = elf
-        .section_header_iter()
-        .filter(|s| {
-            s.sh.sh_type() == SectionType::SHT_PROGBITS
-                && s.sh.flags().contains(SectionHeaderFlags::SHF_ALLOC)
-                && phdr_for_section(s.sh, elf.program_headers())
-                    .map(|phdr| phdr.ph_type() == ProgramType::LOAD)
-                    .unwrap_or(true)
-                && s.sh.size() != 0
-        })
-        .map(|s| Section::new(s, elf.program_headers()))
-        .collect();
+pub enum ElfError {
+    /// The loadable sections span more bytes than the MCU has flash.
+    TooLarge(usize),
+    /// A loadable section's physical address range isn't inside flash, as happens when a linker
+    /// script places data with a RAM load address.
+    SectionOutsideFlash { name: String, addr: u32 },
+    /// Two loadable sections share flash from `addr` on, which only a broken linker script does.
+    /// `a` is the one loaded lower.
+    OverlappingSections { a: String, b: String, addr: u32 },
+    /// An allocated section at `addr` isn't inside any program header, so there is no telling
+    /// where in flash it belongs.
+    NoProgramHeaderForSection { name: String, addr: u32 },
+    /// The ELF's target MCU note names a different MCU than the one being programmed.
+    WrongMcu(String),
+    /// The ELF is not little-endian (`EI_DATA` is not `ELFDATA2LSB`), as ARM Cortex-M parts are.
+    WrongEndianness,
+}
+
+/// Owner name of the note giving the MCU an ELF was built for.
+const MCU_NOTE_OWNER: &[u8] = b"Teensy";
+/// Note type of the note giving the MCU an ELF was built for.
+const NT_TEENSY_MCU: u32 = 1;
+
+/// Reads the MCU `elf` says it was built for, if it says.
+///
+/// Build systems can record this in an `SHT_NOTE` section, conventionally named
+/// `.note.teensy.mcu`, holding a standard ELF note with owner `"Teensy"`, type 1 and the MCU's
+/// name as its descriptor. The name is anything `parse_mcu` accepts, such as `"mk20dx256"` or
+/// `"TEENSY31"`, and may be NUL terminated. ELFs without such a note return `None`.
+pub fn elf_target_mcu(elf: &Elf32) -> Option<String> {
+    fn align4(n: usize) -> usize {
+        (n + 3) & !3
+    }
+
+    for section in elf.section_header_iter() {
+        if section.sh.sh_type() != SectionType::SHT_NOTE {
+            continue;
+        }
+        let notes = section.segment();
+        let mut at = 0;
+        while let (Some(namesz), Some(descsz), Some(note_type)) = (
+            read_u32(notes, at),
+            read_u32(notes, at + 4),
+            read_u32(notes, at + 8),
+        ) {
+            let name_at = at + 12;
+            let desc_at = name_at + align4(namesz as usize);
+            let name = notes.get(name_at..name_at + namesz as usize)?;
+            let desc = notes.get(desc_at..desc_at + descsz as usize)?;
+            if name.split(|&b| b == 0).next() == Some(MCU_NOTE_OWNER) && note_type == NT_TEENSY_MCU
+            {
+                let desc = desc.split(|&b| b == 0).next().unwrap_or(desc);
+                return Some(String::from_utf8_lossy(desc).into_owned());
+            }
+            at = desc_at + align4(descsz as usize);
+        }
+    }
+    None
+}
+
+/// Flattens the loadable contents of `elf` into an image of `mcu.code_size` bytes.
+///
+/// Only `SHT_PROGBITS` sections with `SHF_ALLOC` set are copied. `SHT_NOBITS` sections such as
+/// `.bss` have no contents in the file and are zeroed at runtime, so they are skipped entirely:
+/// they neither move the image's base address nor count as touched, even when
+/// they sit between two loaded sections. Gaps are left as 0xFF, see `elf32_to_bytes_with` to
+/// change that.
+///
+/// The sections may be spread over several disjoint `PT_LOAD` segments. They are collected as
+/// separate regions first and only laid out into the image, each at its load address, once the
+/// distance from the lowest to the highest byte is known to fit in flash. Every section
+/// must also load within flash, anything else is rejected with `ElfError::SectionOutsideFlash`.
+///
+/// If the ELF names the MCU it was built for (see `elf_target_mcu`), that has to be `mcu`.
+pub fn elf32_to_bytes(elf: &Elf32, mcu: &Mcu) -> Result<(Vec<u8>, TouchedMap), ElfError> {
+    elf32_to_bytes_relocated(elf, mcu, 0)
+}
 
-    let mut data = vec![0xFF; mcu.code_size];
-    let mut len = 0;
+/// Like `elf32_to_bytes`, but with every segment's physical address offset by `base`.
+///
+/// Position independent (`ET_DYN`) ELFs are linked at 0, so `base` is where they should end up: a
+/// section at 0x100 in the file is placed at `base + 0x100` in the image.
+pub fn elf32_to_bytes_relocated(
+    elf: &Elf32,
+    mcu: &Mcu,
+    base: u32,
+) -> Result<(Vec<u8>, TouchedMap), ElfError> {
+    let options = LoadOptions {
+        base: Some(base),
+        ..Default::default()
+    };
+    elf32_to_bytes_with(elf, mcu, &options)
+}
+
+/// The sections of `elf` that end up in flash, with `base` added to their load addresses.
+fn loadable_sections<'a>(elf: &'a Elf32<'a>, base: u32) -> Result<Vec<Section<'a>>, ElfError> {
+    let phdrs = program_headers(elf);
+    let mut sections = Vec::new();
+    for s in elf.section_header_iter() {
+        if s.sh.sh_type() != SectionType::SHT_PROGBITS
+            || !s.sh.flags().contains(SectionHeaderFlags::SHF_ALLOC)
+            // Some toolchains emit empty allocated sections at arbitrary addresses, they would
+            // only drag the bounds around
+            || s.sh.size() == 0
+        {
+            continue;
+        }
+        match phdr_for_section(s.sh, &phdrs) {
+            Some(phdr) if phdr.ph_type == ProgramType::LOAD => {
+                sections.push(Section::new(s, phdr, base)?)
+            }
+            Some(_) => {}
+            // The section's address is where it runs, which for initialised data is RAM. Only
+            // the segment says where in flash it is stored.
+            None => {
+                return Err(ElfError::NoProgramHeaderForSection {
+                    name: section_name(&s),
+                    addr: s.sh.addr(),
+                })
+            }
+        }
+    }
+    Ok(sections)
+}
+
+/// Works out which MCU the ELF at `file_path` is for, for when the user doesn't say.
+///
+/// Uses the target MCU note if there is one (see `elf_target_mcu`), otherwise guesses from the
+/// highest address loaded with `infer_mcu_from_image`. Returns `None` for anything that isn't a
+/// little-endian ARM ELF, and for PIEs, whose addresses aren't known until they are given a base.
+pub fn infer_mcu_from_file(file_path: &str) -> Option<InferredMcu> {
+    infer_mcu_from_bytes(&std::fs::read(file_path).ok()?)
+}
+
+/// Like `infer_mcu_from_file`, for an ELF already in memory.
+pub fn infer_mcu_from_bytes(data: &[u8]) -> Option<InferredMcu> {
+    let file_buf = AlignedBytes::new(data);
+    let elf = match Elf::from_bytes(file_buf.as_bytes()) {
+        Ok(Elf::Elf32(elf)) => elf,
+        _ => return None,
+    };
+    if elf.header().endianness() != ElfEndian::LittleEndian
+        || elf.header().machine() != ElfMachine::ARM
+    {
+        return None;
+    }
+
+    if let Some(target) = elf_target_mcu(&elf) {
+        let mcu = parse_mcu(&target)?;
+        return MCUS
+            .iter()
+            .find(|&(_, m)| *m == mcu)
+            .map(|&(name, _)| InferredMcu::Named(name));
+    }
+    if elf.header().elftype() != ElfType::ET_EXEC {
+        return None;
+    }
+    let end = loadable_sections(&elf, 0)
+        .ok()?
+        .iter()
+        .map(|s| s.load_addr as usize + s.size as usize)
+        .max()?;
+    infer_mcu_from_image(end).map(InferredMcu::Guessed)
+}
+
+/// Like `elf32_to_bytes`, but relocated by `options.base` as `elf32_to_bytes_relocated` is and
+/// with the gaps filled with `options.fill_byte`.
+///
+/// Unlike `load_file_with`, this applies `options.base` whatever the ELF's type.
+pub fn elf32_to_bytes_with(
+    elf: &Elf32,
+    mcu: &Mcu,
+    options: &LoadOptions,
+) -> Result<(Vec<u8>, TouchedMap), ElfError> {
+    let base = options.base.unwrap_or(0);
+    if let Some(target) = elf_target_mcu(elf) {
+        if parse_mcu(&target) != Some(*mcu) {
+            return Err(ElfError::WrongMcu(target));
+        }
+    }
+
+    let sections = loadable_sections(elf, base)?;
 
     let base_addr = sections.iter().map(|s| s.load_addr as usize).min().unwrap();
+    let end_addr = sections
+        .iter()
+        .map(|s| s.load_addr as usize + s.size as usize)
+        .max()
+        .unwrap();
+    if end_addr - base_addr > mcu.code_size {
+        return Err(ElfError::TooLarge(end_addr - base_addr));
+    }
+    // Flash starts at address 0 on every supported part
+    if let Some(section) = sections
+        .iter()
+        .find(|s| s.load_addr as usize + s.size as usize > mcu.code_size)
+    {
+        return Err(ElfError::SectionOutsideFlash {
+            name: section_name(&section.shdr),
+            addr: section.load_addr,
+        });
+    }
+    let mut by_addr: Vec<_> = sections.iter().collect();
+    by_addr.sort_by_key(|s| s.load_addr);
+    if let Some(pair) = by_addr
+        .windows(2)
+        .find(|pair| pair[0].load_addr + pair[0].size > pair[1].load_addr)
+    {
+        return Err(ElfError::OverlappingSections {
+            a: section_name(&pair[0].shdr),
+            b: section_name(&pair[1].shdr),
+            addr: pair[1].load_addr,
+        });
+    }
+
+    let mut data = vec![options.fill_byte.unwrap_or(DEFAULT_FILL_BYTE); mcu.code_size];
+    let mut touched = TouchedMap::new(mcu.code_size);
     for section in sections {
-        let start = section.load_addr as usize - base_addr;
+        let start = section.load_addr as usize;
         let end = start + section.size as usize;
-        len += end - start;
+        touched.mark(start..end);
         data[start..end].copy_from_slice(section.shdr.segment());
     }
-    Ok((data, len))
+    Ok((data, touched))
 }
 
 #[cfg(test)]
@@ -337,4 +1257,815 @@ mod tests {
         let names = supported_mcus();
         assert_eq!(expected_names, names);
     }
+
+    /// A loadable section for `build_elf`, each gets its own `PT_LOAD` program header.
+    enum TestSection<'a> {
+        /// `SHT_PROGBITS` at an address
+        Progbits(u32, &'a [u8]),
+        /// `SHT_NOBITS` at an address, with a size
+        Nobits(u32, u32),
+        /// `SHT_NOTE` with the given contents, in a `PT_NOTE` rather than a `PT_LOAD`
+        Note(&'a [u8]),
+        /// `SHT_PROGBITS` that runs at the first address but is stored at the second, as `.data` is
+        Data(u32, u32, &'a [u8]),
+        /// `SHT_PROGBITS` at an address, without a program header
+        Unloaded(u32, &'a [u8]),
+    }
+
+    /// Builds a minimal little endian ARM ELF containing `sections`.
+    fn build_elf(elftype: u16, sections: &[TestSection]) -> Vec<u8> {
+        fn push16(buf: &mut Vec<u8>, v: u16) {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        fn push32(buf: &mut Vec<u8>, v: u32) {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        const SHSTRTAB: &[u8] = b"\0.shstrtab\0";
+        let phnum = sections
+            .iter()
+            .filter(|s| !matches!(s, TestSection::Unloaded(..)))
+            .count();
+        // (type, addr, load address if it has a program header, file offset, file size,
+        // memory size)
+        let mut layout = Vec::new();
+        let mut data = Vec::new();
+        let data_off = 52 + 32 * phnum;
+        for section in sections {
+            let off = (data_off + data.len()) as u32;
+            match *section {
+                TestSection::Progbits(addr, bytes) => {
+                    let len = bytes.len() as u32;
+                    layout.push((1, addr, Some(addr), off, len, len));
+                    data.extend_from_slice(bytes);
+                }
+                TestSection::Nobits(addr, size) => layout.push((8, addr, Some(addr), off, 0, size)),
+                TestSection::Note(bytes) => {
+                    let len = bytes.len() as u32;
+                    layout.push((7, 0, Some(0), off, len, len));
+                    data.extend_from_slice(bytes);
+                }
+                TestSection::Data(addr, load_addr, bytes) => {
+                    let len = bytes.len() as u32;
+                    layout.push((1, addr, Some(load_addr), off, len, len));
+                    data.extend_from_slice(bytes);
+                }
+                TestSection::Unloaded(addr, bytes) => {
+                    let len = bytes.len() as u32;
+                    layout.push((1, addr, None, off, len, len));
+                    data.extend_from_slice(bytes);
+                }
+            }
+        }
+        let shstrtab_off = data_off + data.len();
+        let shdr_off = (shstrtab_off + SHSTRTAB.len() + 3) & !3;
+
+        let mut buf = vec![0x7F, b'E', b'L', b'F', 1, 1, 1];
+        buf.resize(16, 0);
+        push16(&mut buf, elftype);
+        push16(&mut buf, 40); // ARM
+        push32(&mut buf, 1);
+        push32(&mut buf, 0); // entry
+        push32(&mut buf, 52); // phoff
+        push32(&mut buf, shdr_off as u32);
+        push32(&mut buf, 0); // flags
+        push16(&mut buf, 52);
+        push16(&mut buf, 32);
+        push16(&mut buf, phnum as u16);
+        push16(&mut buf, 40);
+        push16(&mut buf, sections.len() as u16 + 2);
+        push16(&mut buf, sections.len() as u16 + 1); // shstrndx
+
+        // PT_LOAD (or PT_NOTE for notes), R+W+X
+        for &(sh_type, addr, load_addr, off, filesz, memsz) in &layout {
+            let p_type = if sh_type == 7 { 4 } else { 1 };
+            if let Some(load_addr) = load_addr {
+                for &v in &[p_type, off, addr, load_addr, filesz, memsz, 7, 4] {
+                    push32(&mut buf, v);
+                }
+            }
+        }
+
+        buf.extend_from_slice(&data);
+        buf.extend_from_slice(SHSTRTAB);
+        buf.resize(shdr_off, 0);
+
+        // Null, the sections (ALLOC + WRITE + EXEC) and .shstrtab (STRTAB)
+        buf.resize(buf.len() + 40, 0);
+        for &(sh_type, addr, _, off, _, memsz) in &layout {
+            for &v in &[0, sh_type, 7, addr, off, memsz, 0, 0, 4, 0] {
+                push32(&mut buf, v);
+            }
+        }
+        for &v in &[
+            1,
+            3,
+            0,
+            0,
+            shstrtab_off as u32,
+            SHSTRTAB.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ] {
+            push32(&mut buf, v);
+        }
+
+        buf
+    }
+
+    #[test]
+    fn pie_elf_needs_base() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let path = std::env::temp_dir().join("rusty_loader_pie_elf_needs_base");
+        std::fs::write(
+            &path,
+            build_elf(3, &[TestSection::Progbits(0, &[1, 2, 3, 4])]),
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        match load_file(path, FileHint::Any, &mcu) {
+            Err(LoadError::NeedsBase) => {}
+            res => panic!(
+                "expected NeedsBase, got {:?}",
+                res.map(|(_, t, _)| t.written())
+            ),
+        }
+
+        let options = LoadOptions {
+            base: Some(0x400),
+            ..Default::default()
+        };
+        let (bytes, touched, file_type) =
+            load_file_with(path, FileHint::Any, &mcu, &options).unwrap();
+        assert_eq!(file_type, FileHint::ELF);
+        assert_eq!(touched.written(), 4);
+        assert_eq!(&bytes[0x400..0x404], &[1, 2, 3, 4]);
+        assert_eq!(&bytes[..4], &[0xFF; 4]);
+    }
+
+    #[test]
+    fn big_endian_elf_rejected() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let mut buf = build_elf(2, &[TestSection::Progbits(0, &[1, 2, 3, 4])]);
+        buf[5] = 2; // ELFDATA2MSB
+        let path = std::env::temp_dir().join("rusty_loader_big_endian_elf_rejected");
+        std::fs::write(&path, buf).unwrap();
+
+        match load_file(path.to_str().unwrap(), FileHint::Any, &mcu) {
+            Err(LoadError::InvalidElf(ElfError::WrongEndianness)) => {}
+            res => panic!(
+                "expected WrongEndianness, got {:?}",
+                res.map(|(_, t, _)| t.written())
+            ),
+        }
+    }
+
+    #[test]
+    fn elf_bss_between_sections() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 8]),
+                TestSection::Nobits(8, 0x100),
+                TestSection::Progbits(0x108, &[2; 4]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+
+        let (bytes, touched) = elf32_to_bytes(&elf, &mcu).unwrap();
+        assert_eq!(touched.written(), 12);
+        assert_eq!(touched.span(), Some(0..0x10C));
+        assert_eq!(&bytes[..8], &[1; 8]);
+        assert!(bytes[8..0x108].iter().all(|&b| b == 0xFF));
+        assert_eq!(&bytes[0x108..0x10C], &[2; 4]);
+        assert!(bytes[0x10C..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn mcu_table_is_consistent() {
+        for (name, mcu) in MCUS.iter() {
+            assert!(
+                usb::BLOCK_SIZES.contains(&mcu.block_size),
+                "{} has unsupported block size {}",
+                name,
+                mcu.block_size
+            );
+            assert_eq!(
+                mcu.code_size % mcu.block_size,
+                0,
+                "{} code size is not a whole number of blocks",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn const_mcu_lookup() {
+        const TEENSY31: Mcu = mcu!("TEENSY31");
+        assert_eq!(
+            TEENSY31,
+            Mcu {
+                code_size: 262144,
+                block_size: 1024,
+            }
+        );
+        assert_eq!(mcu!("mk20dx256"), TEENSY31);
+        assert_eq!(mcu_by_name("TEENSY3"), None);
+        assert_eq!(mcu_by_name("mk20dx25"), None);
+    }
+
+    #[test]
+    fn capabilities_are_write_only() {
+        for (name, mcu) in MCUS.iter() {
+            let caps = capabilities(mcu);
+            assert!(caps.supports_erase, "{} can't erase", name);
+            assert!(!caps.supports_read && !caps.supports_verify && !caps.supports_eeprom);
+        }
+        assert!(!crate_version().is_empty());
+    }
+
+    #[test]
+    fn board_names_for_mcu() {
+        let mcu = parse_mcu("mk20dx256").unwrap();
+        assert_eq!(mcu_to_board_names(&mcu), vec!["TEENSY31", "TEENSY32"]);
+        let mcu = parse_mcu("at90usb162").unwrap();
+        assert!(mcu_to_board_names(&mcu).is_empty());
+    }
+
+    #[test]
+    fn aliases_and_chips() {
+        assert_eq!(aliases_for("mk20dx256"), vec!["TEENSY31", "TEENSY32"]);
+        assert_eq!(aliases_for("mkl26z64"), vec!["TEENSYLC"]);
+        assert!(aliases_for("at90usb162").is_empty());
+        assert!(aliases_for("TEENSY31").is_empty());
+
+        assert_eq!(chip_for_alias("TEENSY31"), Some("mk20dx256"));
+        assert_eq!(chip_for_alias("TEENSY32"), Some("mk20dx256"));
+        assert_eq!(chip_for_alias("mk20dx256"), None);
+
+        for &(alias, name) in ALIASES.iter() {
+            assert!(aliases_for(chip_for_alias(alias).unwrap()).contains(&alias));
+            assert!(MCUS.iter().any(|&(n, _)| n == name));
+        }
+    }
+
+    #[test]
+    fn diff_flash_images() {
+        let a = vec![0xFF; 0x1000];
+        let mut b = a.clone();
+        assert_eq!(diff_images(&a, &b, 0x100), vec![]);
+
+        b[0x10] = 0;
+        b[0x1FF] = 0;
+        b[0x100] = 0;
+        b[0x800] = 0;
+        assert_eq!(diff_images(&a, &b, 0x100), vec![0..0x200, 0x800..0x900]);
+        assert_eq!(
+            diff_images(&a, &b[..0x900], 0x100),
+            vec![0..0x200, 0x800..0x1000]
+        );
+    }
+
+    #[test]
+    fn usage_counts_span() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let mut touched = TouchedMap::new(mcu.code_size);
+        assert_eq!(usage(&touched, &mcu), 0.0);
+
+        touched.mark(0..0x400);
+        touched.mark(0x1FC00..0x20000);
+        assert_eq!(touched.written(), 0x800);
+        assert_eq!(usage(&touched, &mcu), 50.0);
+    }
+
+    #[test]
+    fn elf_data_stored_in_flash() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        // .data runs in RAM but is stored right after the code
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Data(0x1FFF_8000, 0x10, &[2; 8]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        let (bytes, touched) = elf32_to_bytes(&elf, &mcu).unwrap();
+        assert_eq!(touched.span(), Some(0..0x18));
+        assert_eq!(&bytes[0x10..0x18], &[2; 8]);
+
+        // Without its segment there's only the RAM address to go on
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Unloaded(0x1FFF_8000, &[2; 8]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        match elf32_to_bytes(&elf, &mcu) {
+            Err(ElfError::NoProgramHeaderForSection {
+                addr: 0x1FFF_8000, ..
+            }) => {}
+            res => panic!(
+                "expected NoProgramHeaderForSection, got {:?}",
+                res.map(|(_, t)| t.written())
+            ),
+        }
+    }
+
+    #[test]
+    fn elf_overlapping_sections() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0x400, &[2; 8]),
+                TestSection::Progbits(0, &[1; 0x408]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        match elf32_to_bytes(&elf, &mcu) {
+            Err(ElfError::OverlappingSections { addr: 0x400, .. }) => {}
+            res => panic!(
+                "expected OverlappingSections, got {:?}",
+                res.map(|(_, t)| t.written())
+            ),
+        }
+
+        // Sections that only touch are fine
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0x400, &[2; 8]),
+                TestSection::Progbits(0, &[1; 0x400]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        let (_, touched) = elf32_to_bytes(&elf, &mcu).unwrap();
+        assert_eq!(touched.span(), Some(0..0x408));
+    }
+
+    #[test]
+    fn elf_empty_sections_ignored() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        // Empty sections below the code and out in RAM, neither may move or reject the image
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0x100, &[]),
+                TestSection::Progbits(0x400, &[1, 2, 3, 4]),
+                TestSection::Progbits(0x1FFF_8000, &[]),
+            ],
+        );
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        let (bytes, touched) = elf32_to_bytes(&elf, &mcu).unwrap();
+        assert_eq!(touched.span(), Some(0x400..0x404));
+        assert_eq!(&bytes[0x400..0x404], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn elf_section_outside_flash() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        // Small enough to fit, but loaded into RAM
+        let buf = build_elf(2, &[TestSection::Progbits(0x1FFF_8000, &[1; 16])]);
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        match elf32_to_bytes(&elf, &mcu) {
+            Err(ElfError::SectionOutsideFlash {
+                addr: 0x1FFF_8000, ..
+            }) => {}
+            res => panic!(
+                "expected SectionOutsideFlash, got {:?}",
+                res.map(|(_, t)| t.written())
+            ),
+        }
+    }
+
+    #[test]
+    fn elf_base_overflow_outside_flash() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let buf = build_elf(3, &[TestSection::Progbits(0x100, &[1; 16])]);
+        let elf = match Elf::from_bytes(&buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        match elf32_to_bytes_relocated(&elf, &mcu, u32::MAX - 0x10) {
+            Err(ElfError::SectionOutsideFlash { addr: 0x100, .. }) => {}
+            res => panic!(
+                "expected SectionOutsideFlash, got {:?}",
+                res.map(|(_, t)| t.written())
+            ),
+        }
+    }
+
+    #[test]
+    fn infer_mcu() {
+        assert_eq!(infer_mcu_from_image(0x400), Some("mkl26z64"));
+        assert_eq!(infer_mcu_from_image(0x40000), Some("mk20dx256"));
+        assert_eq!(infer_mcu_from_image(0x40001), Some("mk64fx512"));
+        assert_eq!(infer_mcu_from_image(0x100001), None);
+
+        let elf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Progbits(0x1F000, &[2; 16]),
+            ],
+        );
+        assert_eq!(
+            infer_mcu_from_bytes(&elf),
+            Some(InferredMcu::Guessed("mk20dx128"))
+        );
+
+        let mut note = Vec::new();
+        note.extend_from_slice(&7u32.to_le_bytes());
+        note.extend_from_slice(&8u32.to_le_bytes());
+        note.extend_from_slice(&1u32.to_le_bytes());
+        note.extend_from_slice(b"Teensy\0\0TEENSY36");
+        let elf = build_elf(
+            2,
+            &[TestSection::Progbits(0, &[1; 16]), TestSection::Note(&note)],
+        );
+        assert_eq!(
+            infer_mcu_from_bytes(&elf),
+            Some(InferredMcu::Named("mk66fx1m0"))
+        );
+
+        // PIEs can be anywhere
+        let elf = build_elf(3, &[TestSection::Progbits(0, &[1; 16])]);
+        assert_eq!(infer_mcu_from_bytes(&elf), None);
+    }
+
+    #[test]
+    fn elf_target_mcu_note() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let parse = |buf| match Elf::from_bytes(buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+        let note = |desc: &[u8]| {
+            let mut note = Vec::new();
+            note.extend_from_slice(&7u32.to_le_bytes());
+            note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+            note.extend_from_slice(&1u32.to_le_bytes());
+            note.extend_from_slice(b"Teensy\0\0");
+            note.extend_from_slice(desc);
+            note.resize((note.len() + 3) & !3, 0);
+            note
+        };
+        let buf = build_elf(2, &[TestSection::Progbits(0, &[1; 16])]);
+        assert_eq!(elf_target_mcu(&parse(&buf)), None);
+
+        let matching = note(b"mk20dx256\0");
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Note(&matching),
+            ],
+        );
+        let elf = parse(&buf);
+        assert_eq!(elf_target_mcu(&elf).as_deref(), Some("mk20dx256"));
+        assert!(elf32_to_bytes(&elf, &mcu).is_ok());
+
+        let other = note(b"TEENSYLC");
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Note(&other),
+            ],
+        );
+        assert_eq!(
+            elf32_to_bytes(&parse(&buf), &mcu).map(|_| ()),
+            Err(ElfError::WrongMcu("TEENSYLC".to_string()))
+        );
+    }
+
+    #[test]
+    fn elf_disjoint_load_segments() {
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let parse = |buf| match Elf::from_bytes(buf) {
+            Ok(Elf::Elf32(elf)) => elf,
+            _ => panic!("failed to parse test ELF"),
+        };
+
+        // Code at the bottom of flash and a config block right at the top
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Progbits(0x3FFF0, &[2; 16]),
+            ],
+        );
+        let (bytes, touched) = elf32_to_bytes(&parse(&buf), &mcu).unwrap();
+        assert_eq!(touched.written(), 32);
+        assert_eq!(&bytes[..16], &[1; 16]);
+        assert!(bytes[16..0x3FFF0].iter().all(|&b| b == 0xFF));
+        assert_eq!(&bytes[0x3FFF0..], &[2; 16]);
+
+        // One byte further and the two no longer fit
+        let buf = build_elf(
+            2,
+            &[
+                TestSection::Progbits(0, &[1; 16]),
+                TestSection::Progbits(0x3FFF1, &[2; 16]),
+            ],
+        );
+        assert_eq!(
+            elf32_to_bytes(&parse(&buf), &mcu).map(|_| ()),
+            Err(ElfError::TooLarge(0x40001))
+        );
+    }
+
+    #[test]
+    fn detect_file_format() {
+        assert_eq!(detect_format(b"\x7FELF\x01\x01"), Some(FileHint::ELF));
+        assert_eq!(detect_format(b":10000000"), Some(FileHint::IHEX));
+        assert_eq!(detect_format(b"\r\n  :00000001FF"), Some(FileHint::IHEX));
+        assert_eq!(detect_format(b"S00F000068656C6C6F"), None);
+        assert_eq!(detect_format(b""), None);
+    }
+
+    #[test]
+    fn custom_file_format() {
+        /// Raw binary with a 4 byte magic number.
+        struct Tagged;
+
+        impl FileFormat for Tagged {
+            fn name(&self) -> &'static str {
+                "tagged binary"
+            }
+
+            fn hint(&self) -> FileHint {
+                FileHint::Any
+            }
+
+            fn detect(&self, data: &[u8]) -> bool {
+                data.starts_with(b"TAG:")
+            }
+
+            fn to_image(
+                &self,
+                data: &[u8],
+                mcu: &Mcu,
+                _options: &LoadOptions,
+            ) -> Result<(Vec<u8>, TouchedMap), LoadError> {
+                let data = data.strip_prefix(b"TAG:").ok_or(LoadError::NotValidFile)?;
+                let mut bin = vec![0xFF; mcu.code_size];
+                bin[..data.len()].copy_from_slice(data);
+                let mut touched = TouchedMap::new(mcu.code_size);
+                touched.mark(0..data.len());
+                Ok((bin, touched))
+            }
+        }
+
+        let mcu = parse_mcu("TEENSY31").unwrap();
+        let formats: &[&dyn FileFormat] = &[&Tagged, &ElfFormat, &IHexFormat];
+
+        let (bin, touched, format) =
+            parse_image_as(b"TAG:\x01\x02", formats, &mcu, &LoadOptions::default()).unwrap();
+        assert_eq!(format.name(), "tagged binary");
+        assert_eq!(&bin[..3], &[1, 2, 0xFF]);
+        assert_eq!(touched.written(), 2);
+
+        let (_, _, format) =
+            parse_image_as(b":00000001FF", formats, &mcu, &LoadOptions::default()).unwrap();
+        assert_eq!(format.hint(), FileHint::IHEX);
+
+        // Built in formats don't know it
+        assert!(matches!(
+            parse_image(b"TAG:\x01", FileHint::Any, &mcu, &LoadOptions::default()),
+            Err(LoadError::NotValidFile)
+        ));
+    }
+
+    #[test]
+    fn vector_table_check() {
+        let lc = parse_mcu("TEENSYLC").unwrap();
+        let (blink, _, _) = load_file("tests/blink", FileHint::ELF, &lc).unwrap();
+        assert_eq!(check_vector_table(&blink, &lc), None);
+
+        let mut image = blink.clone();
+        image[..8].copy_from_slice(&[0; 8]);
+        assert_eq!(
+            check_vector_table(&image, &lc),
+            Some(VectorTableProblem::BadStackPointer(0))
+        );
+
+        // Even (ARM rather than Thumb) reset vector
+        image[..8].copy_from_slice(&[0x00, 0x18, 0x00, 0x20, 0xE8, 0x05, 0x00, 0x00]);
+        assert_eq!(
+            check_vector_table(&image, &lc),
+            Some(VectorTableProblem::BadResetVector(0x5E8))
+        );
+
+        // AVR parts have no vector table to check
+        let teensy2 = parse_mcu("TEENSY2").unwrap();
+        assert_eq!(check_vector_table(&[0; 128], &teensy2), None);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn ihex_segment_offset_wraps() {
+        let mcu = parse_mcu("TEENSY30").unwrap();
+        let recs = [
+            IHexRecord::ExtendedSegmentAddress(0x800),
+            IHexRecord::Data {
+                offset: 0xFFFE,
+                value: vec![1, 2, 3, 4],
+            },
+            IHexRecord::EndOfFile,
+        ];
+
+        let (bytes, touched) = ihex_to_bytes(&recs, &mcu).unwrap();
+        assert_eq!(touched.written(), 4);
+        assert_eq!(&bytes[0x17FFE..0x18000], &[1, 2]);
+        assert_eq!(&bytes[0x8000..0x8002], &[3, 4]);
+        assert_eq!(bytes[0x18000], 0xFF);
+    }
+
+    #[test]
+    fn ihex_address_too_high() {
+        let mcu = parse_mcu("TEENSY30").unwrap();
+        let data = |offset| IHexRecord::Data {
+            offset,
+            value: vec![0; 0x100],
+        };
+
+        // Filling flash to the very last byte is fine
+        let recs = [
+            IHexRecord::ExtendedLinearAddress(1),
+            data(0xFF00),
+            IHexRecord::EndOfFile,
+        ];
+        assert!(ihex_to_bytes(&recs, &mcu).is_ok());
+
+        // The error gives the furthest address, not the first one out of range
+        let recs = [
+            IHexRecord::ExtendedLinearAddress(2),
+            data(0x0000),
+            data(0x0100),
+            IHexRecord::ExtendedLinearAddress(1),
+            data(0x0000),
+            IHexRecord::EndOfFile,
+        ];
+        assert_eq!(
+            ihex_to_bytes(&recs, &mcu),
+            Err(IHexError::AddressTooHigh(0x20200))
+        );
+    }
+
+    #[test]
+    fn mcu_suggestion() {
+        let teensy30 = parse_mcu("TEENSY30").unwrap();
+        assert_eq!(
+            smallest_mcu_fitting(&teensy30, 0x40100),
+            Some(("TEENSY35", parse_mcu("mk64fx512").unwrap()))
+        );
+        assert_eq!(
+            smallest_mcu_fitting(&teensy30, 0x20000),
+            Some(("TEENSY30", teensy30))
+        );
+        assert_eq!(smallest_mcu_fitting(&teensy30, 0x100001), None);
+
+        // AVR images only run on AVRs
+        let teensy2 = parse_mcu("TEENSY2").unwrap();
+        assert_eq!(
+            smallest_mcu_fitting(&teensy2, 0x10000),
+            Some(("TEENSY2PP", parse_mcu("at90usb1286").unwrap()))
+        );
+    }
+
+    #[test]
+    fn dump_ihex_records() {
+        let data = b":020000040001F9\n:0401000001020304F1\n:04zz\n";
+        let mut out = Vec::new();
+        dump_records(data, FileHint::Any, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ExtendedLinearAddress 0x0001\n\
+             Data offset 0x0100 len 4\n\
+             error: invalid characters encountered in record\n"
+        );
+    }
+
+    #[test]
+    fn ihex_fill_byte() {
+        let mcu = parse_mcu("TEENSY30").unwrap();
+        let recs = [
+            IHexRecord::Data {
+                offset: 0x10,
+                value: vec![1, 2],
+            },
+            IHexRecord::EndOfFile,
+        ];
+        let options = LoadOptions {
+            fill_byte: Some(0),
+            ..Default::default()
+        };
+
+        let (bytes, touched) = ihex_to_bytes_with(&recs, &mcu, &options).unwrap();
+        assert_eq!(touched.written(), 2);
+        assert_eq!(&bytes[0x10..0x12], &[1, 2]);
+        assert!(bytes[..0x10].iter().chain(&bytes[0x12..]).all(|&b| b == 0));
+    }
+
+    #[test]
+    fn ihex_round_trip() {
+        let mcu = parse_mcu("TEENSY30").unwrap();
+        // Long enough to need an extended linear address record
+        let image: Vec<u8> = (0..0x11234).map(|n| (n % 251) as u8).collect();
+
+        let recs = bytes_to_ihex(&image, 0);
+        assert!(recs.contains(&IHexRecord::ExtendedLinearAddress(1)));
+        assert_eq!(recs.last(), Some(&IHexRecord::EndOfFile));
+
+        let (bytes, touched) = ihex_to_bytes(&recs, &mcu).unwrap();
+        assert_eq!(touched.written(), image.len());
+        assert_eq!(&bytes[..image.len()], &image[..]);
+        assert!(bytes[image.len()..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn ihex_record_len() {
+        let mcu = parse_mcu("TEENSY30").unwrap();
+        let image: Vec<u8> = (0..0x1234).map(|n| (n % 251) as u8).collect();
+
+        let narrow = bytes_to_ihex_with(&image, 0, 16);
+        let wide = bytes_to_ihex_with(&image, 0, 32);
+        assert_eq!(narrow, bytes_to_ihex(&image, 0));
+        for (recs, len) in [(&narrow, 16), (&wide, 32)] {
+            assert!(recs.iter().all(|rec| match rec {
+                IHexRecord::Data { value, .. } => value.len() <= len,
+                _ => true,
+            }));
+        }
+        assert!(wide.len() < narrow.len());
+
+        assert_eq!(
+            ihex_to_bytes(&narrow, &mcu).unwrap().0,
+            ihex_to_bytes(&wide, &mcu).unwrap().0
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn ihex_record_len_too_long() {
+        bytes_to_ihex_with(&[0; 512], 0, 256);
+    }
+
+    #[test]
+    fn ihex_records_do_not_cross_64k() {
+        let recs = bytes_to_ihex(&[0; 32], 0xFFF8);
+        assert_eq!(
+            recs,
+            vec![
+                IHexRecord::Data {
+                    offset: 0xFFF8,
+                    value: vec![0; 8],
+                },
+                IHexRecord::ExtendedLinearAddress(1),
+                IHexRecord::Data {
+                    offset: 0,
+                    value: vec![0; 16],
+                },
+                IHexRecord::Data {
+                    offset: 0x10,
+                    value: vec![0; 8],
+                },
+                IHexRecord::EndOfFile,
+            ]
+        );
+    }
 }
@@ -1,13 +1,64 @@
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+use log::{debug, warn, LevelFilter};
 
-use rusty_loader::usb::{ConnectError, ProgramError, Teensy};
-use rusty_loader::{load_file, parse_mcu, supported_mcus, FileHint, LoadError};
+use rusty_loader::usb::{
+    erase_timeout, ConnectError, ConnectOptions, ProgramError, ProgramOptions, Progress,
+    RebootError, Teensy, UsbPath, DEFAULT_BLOCK_TIMEOUT, SEREMU_PRODUCT_IDS, TEENSY_VENDOR_ID,
+    WIN_SYNC_ENV,
+};
+use rusty_loader::{
+    aliases_for, check_vector_table, chip_for_alias, crate_version, crc32, diff_images,
+    dump_records, infer_mcu_from_file, load_file_with, mcu_table, mcu_to_board_names, parse_image,
+    parse_mcu, smallest_mcu_fitting, supported_mcus, usage, ElfError, FileHint, IHexError,
+    InferredMcu, LoadError, LoadOptions, Mcu, VectorTableProblem,
+};
+
+mod config;
+mod spinner;
+mod summary;
+mod timeouts;
+
+use config::{load_config, ConfigError, FIRMWARE_ENV, MCU_ENV};
+use spinner::Spinner;
+use summary::Summary;
+use timeouts::{TimeoutConfig, TimeoutsError, TIMEOUTS_ENV, TIMEOUT_KEYS};
 
 static mut VERBOSE: bool = false;
 
+/// Set by the Ctrl-C handler, checked while waiting for the device. Between blocks it is
+/// `Job::cancel` that gets checked.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Exit codes, so scripts can tell failures apart without parsing messages. Anything not covered
+// below, such as bad arguments or configuration, exits with 1.
+
+/// The firmware file couldn't be read, isn't valid or doesn't fit.
+const EXIT_FILE: i32 = 2;
+
+/// No device in the bootloader showed up. Worth retrying, unlike the others.
+const EXIT_NO_DEVICE: i32 = 3;
+
+/// The device stopped accepting writes while erasing, programming or booting.
+const EXIT_WRITE: i32 = 4;
+
+/// Exit code used when the user interrupts us, matching a shell's 128 + SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Connection attempts made after rebooting a sketch, at `--retry-delay` apart, when neither
+/// `--wait` nor `--retry` says otherwise.
+const REBOOT_RETRIES: u32 = 20;
+
+/// Retries a single block may take before we warn that the USB connection looks unreliable.
+const MARGINAL_LINK_RETRIES: usize = 5;
+
 macro_rules! println_verbose {
     ($($arg:tt)*) => ({
         if unsafe { VERBOSE } {
@@ -28,32 +79,157 @@ macro_rules! print_verbose {
 // TODO: soft reboot
 fn main() {
     let matches = App::new("rusty_loader")
-        .version(option_env!("CARGO_PKG_VERSION").unwrap_or("unknown"))
+        .version(crate_version())
         .author("Gabriel \"yodaldevoid\" Smith <ga29smith@gmail.com>")
         .about("A rust rewrite of teensy_loader_cli")
+        .after_help(
+            "EXIT STATUS:\n    \
+             0    Success\n    \
+             1    Invalid arguments or configuration, or another error\n    \
+             2    The file could not be loaded\n    \
+             3    No device was found\n    \
+             4    Writing to the device failed\n    \
+             130  Interrupted\n\n\
+             ENVIRONMENT:\n    \
+             TEENSY_MCU         Used when --mcu isn't given, before .teensy.toml\n    \
+             TEENSY_FIRMWARE    Used when no file is given, before .teensy.toml\n    \
+             TEENSY_TIMEOUTS    Like --timeouts, for the timings it doesn't give",
+        )
         .arg(
             Arg::with_name("mcu")
                 .long("mcu")
                 .short("m")
-                .help("The microcontroller to operate on")
+                .help("The microcontroller to operate on, worked out from an ELF file if not given")
                 .takes_value(true)
                 .empty_values(false)
-                .required(true)
                 .possible_values(&supported_mcus()),
         )
-        .arg(Arg::with_name("verbose").long("verbose").short("v"))
+        .arg(
+            Arg::with_name("list-mcus")
+                .long("list-mcus")
+                .help("List the supported microcontrollers and boards, then exit"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .short("v")
+                .multiple(true)
+                .help("Print progress information, twice to also trace USB transfers"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .conflicts_with_all(&["verbose", "format"])
+                .help("Only print errors"),
+        )
+        .arg(
+            Arg::with_name("bus")
+                .long("bus")
+                .help("Only use the bootloader on this USB bus (libusb only)")
+                .takes_value(true)
+                .value_name("number")
+                .requires("address"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .long("address")
+                .help("Only use the bootloader at this USB address on --bus (libusb only)")
+                .takes_value(true)
+                .value_name("number")
+                .requires("bus"),
+        )
+        .arg(
+            Arg::with_name("select")
+                .long("select")
+                .help(
+                    "Wait for devices in the bootloader and ask which one to use, \
+                     or use the first when not run interactively (libusb only)",
+                )
+                .conflicts_with_all(&["bus", "repeat"]),
+        )
+        .arg(Arg::with_name("win-sync").long("win-sync").help(
+            "Send reports synchronously, for HID drivers that mishandle overlapped writes \
+                     (Windows only)",
+        ))
         .arg(
             Arg::with_name("wait")
                 .long("wait")
                 .short("w")
                 .help("Wait for the device to appear"),
         )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .short("t")
+                .help(
+                    "Give up waiting for the device after this many milliseconds (implies --wait)",
+                )
+                .takes_value(true)
+                .value_name("ms"),
+        )
+        .arg(
+            Arg::with_name("timeouts")
+                .long("timeouts")
+                .help(
+                    "Timings for each phase in milliseconds, e.g. \
+                     \"first=8000,block=500,boot=1000,settle=0\" \
+                     (first block, other blocks, boot command, pause before booting); \
+                     --settle takes precedence",
+                )
+                .takes_value(true)
+                .value_name("key=ms,..."),
+        )
+        .arg(
+            Arg::with_name("retry")
+                .long("retry")
+                .help("Try connecting this many more times before giving up")
+                .takes_value(true)
+                .value_name("count"),
+        )
+        .arg(
+            Arg::with_name("retry-delay")
+                .long("retry-delay")
+                .help("Pause between connection attempts")
+                .takes_value(true)
+                .value_name("ms")
+                .default_value("250"),
+        )
+        .arg(
+            Arg::with_name("retry-interval")
+                .long("retry-interval")
+                .help("Pause between retries of a write the device hasn't accepted")
+                .takes_value(true)
+                .value_name("ms"),
+        )
+        .arg(
+            Arg::with_name("reboot-method")
+                .long("reboot-method")
+                .help(
+                    "How to get a running sketch into the bootloader before programming. \
+                     `hid` works with Teensyduino sketches built as Keyboard/Mouse/Joystick, \
+                     Raw HID or Flight Sim, anything else needs the reset button",
+                )
+                .takes_value(true)
+                .possible_values(&["none", "hid"])
+                .default_value("none"),
+        )
         .arg(
             Arg::with_name("no-reboot")
                 .long("no-reboot")
                 .short("n")
-                .help("No reboot after programming")
-                .requires("file"),
+                .help("No reboot after programming"),
+        )
+        .arg(
+            Arg::with_name("settle")
+                .long("settle")
+                .help(
+                    "Wait this long after programming before rebooting, \
+                     for boards that sometimes miss the boot command right after a flash",
+                )
+                .takes_value(true)
+                .value_name("ms")
+                .default_value("0"),
         )
         .arg(
             Arg::with_name("boot-only")
@@ -61,13 +237,22 @@ fn main() {
                 .short("b")
                 .help("Only boot the device, do not program"),
         )
+        .arg(
+            Arg::with_name("erase")
+                .long("erase")
+                .short("E")
+                .help("Only erase the device, do not program")
+                .conflicts_with("boot-only")
+                .conflicts_with("file"),
+        )
         .arg(
             Arg::with_name("elf")
                 .long("elf")
                 .short("e")
                 .help("Input file should be treated as an ELF file")
                 .conflicts_with("ihex")
-                .conflicts_with("boot-only"),
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
         )
         .arg(
             Arg::with_name("ihex")
@@ -75,132 +260,1225 @@ fn main() {
                 .short("i")
                 .help("Input file should be treated as an Intel HEX file")
                 .conflicts_with("elf")
-                .conflicts_with("boot-only"),
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("base")
+                .long("base")
+                .help("Load address for position independent ELF files")
+                .takes_value(true)
+                .value_name("addr")
+                .conflicts_with("ihex")
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .help(
+                    "Only program flash from this address up, without erasing \
+                     (the region must already be blank)",
+                )
+                .takes_value(true)
+                .value_name("addr")
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("continue-on-error")
+                .long("continue-on-error")
+                .help("Try every block even if some fail to write, then list the failures")
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("block-delay")
+                .long("block-delay")
+                .help("Pause between blocks, for boards that fail when they come too quickly")
+                .takes_value(true)
+                .value_name("ms")
+                .conflicts_with("boot-only")
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("warn-usage")
+                .long("warn-usage")
+                .help("Warn when the image spans more than this percentage of flash")
+                .takes_value(true)
+                .value_name("percent")
+                .default_value("95"),
+        )
+        .arg(
+            Arg::with_name("fill-byte")
+                .long("fill-byte")
+                .help("Byte to fill the flash the file doesn't cover with, only blocks of 0xFF are skipped")
+                .takes_value(true)
+                .value_name("hex")
+                .default_value("0xFF"),
         )
         .arg(
-            Arg::with_name("file")
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Load and check the file without touching the device")
                 .conflicts_with("boot-only")
-                .required_unless("boot-only"),
+                .conflicts_with("erase"),
+        )
+        .arg(
+            Arg::with_name("check-only")
+                .long("check-only")
+                .help(
+                    "Connect to the device and check it matches --mcu and the file fits, \
+                     but don't write anything",
+                )
+                .conflicts_with_all(&["dry-run", "boot-only", "erase"]),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("How to report the result, json replaces the human readable output")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::with_name("repeat")
+                .long("repeat")
+                .help(
+                    "After flashing a board, wait for the next one and flash that too, \
+                     until interrupted with Ctrl-C",
+                )
+                .conflicts_with_all(&["dry-run", "check-only", "format"]),
+        )
+        .arg(
+            Arg::with_name("dump-records")
+                .long("dump-records")
+                .help("Print the records or headers parsed from the file and exit")
+                .hidden(true),
+        )
+        .arg(
+            Arg::with_name("self-test")
+                .long("self-test")
+                .help("Check that a known ELF and its Intel hex copy load the same, then exit")
+                .hidden(true),
+        )
+        .arg(Arg::with_name("file").conflicts_with("boot-only"))
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Lists the blocks of flash that differ between two files, then exits")
+                .arg(Arg::with_name("old").required(true))
+                .arg(Arg::with_name("new").required(true)),
         )
         .get_matches();
 
-    let mcu = match parse_mcu(matches.value_of("mcu").unwrap()) {
+    let json = matches.value_of("format") == Some("json");
+    if json && !cfg!(feature = "json") {
+        eprintln!("JSON output is not available (hint: rebuild with the `json` feature)");
+        exit(1);
+    }
+
+    // Keep stdout clean for the JSON object, logging still goes to stderr
+    unsafe {
+        VERBOSE = matches.is_present("verbose") && !json;
+    }
+
+    env_logger::Builder::new()
+        .filter_level(match matches.occurrences_of("verbose") {
+            0 if matches.is_present("quiet") => LevelFilter::Error,
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        })
+        .format_timestamp(None)
+        .init();
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = Arc::clone(&cancel);
+    if let Err(err) = ctrlc::set_handler(move || {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        handler_cancel.store(true, Ordering::SeqCst);
+    }) {
+        warn!("Failed to install Ctrl-C handler: {}", err);
+    }
+
+    if matches.is_present("self-test") {
+        exit(if self_test() { 0 } else { 1 });
+    }
+
+    if matches.is_present("list-mcus") {
+        println!("{:<12} {:>10} {:>6}  Boards", "MCU", "Flash", "Block");
+        for (name, mcu) in mcu_table() {
+            let line = format!(
+                "{:<12} {:>6} KiB {:>6}  {}",
+                name,
+                mcu.code_size as f64 / 1024.0,
+                mcu.block_size,
+                aliases_for(name).join(", ")
+            );
+            println!("{}", line.trim_end());
+        }
+        return;
+    }
+
+    let config = match load_config(Path::new(".")) {
+        Ok(config) => config,
+        Err(ConfigError::FailedRead(name, err)) => {
+            eprintln!("Failed to read \"{}\"", name);
+            println_verbose!("Error: {}", err);
+            exit(1);
+        }
+        Err(ConfigError::Parse(name, err)) => {
+            eprintln!("Failed to parse \"{}\": {}", name, err);
+            exit(1);
+        }
+    };
+
+    let env_mcu = std::env::var(MCU_ENV).ok();
+    let env_file = std::env::var(FIRMWARE_ENV).ok();
+
+    let mcu_name = match config.resolve_mcu(matches.value_of("mcu"), env_mcu.as_deref()) {
+        Some(name) => name,
+        // Work it out from the file, if there is one that says enough
+        None => match config
+            .resolve_file(
+                matches
+                    .value_of("file")
+                    .or_else(|| matches.subcommand_matches("diff")?.value_of("old")),
+                env_file.as_deref(),
+            )
+            .and_then(infer_mcu_from_file)
+        {
+            Some(InferredMcu::Named(name)) => {
+                println_verbose!("Using {} from the file's target MCU note", name);
+                name
+            }
+            Some(InferredMcu::Guessed(name)) => {
+                let boards = parse_mcu(name).map_or(Vec::new(), |mcu| mcu_to_board_names(&mcu));
+                warn!(
+                    "No device given, guessed {} ({}) from the file's size; \
+                     a bigger part may have been meant, use --mcu to be explicit",
+                    name,
+                    boards.join(", ")
+                );
+                name
+            }
+            None => {
+                eprintln!(
+                    "No device given (hint: use --mcu, set TEENSY_MCU or set `mcu` in .teensy.toml)"
+                );
+                exit(1);
+            }
+        },
+    };
+    let mcu = match parse_mcu(mcu_name) {
         Some(mcu) => mcu,
         None => {
             eprintln!("Unkown device name");
-            std::process::exit(1);
+            exit(1);
         }
     };
 
-    unsafe {
-        VERBOSE = matches.is_present("verbose");
+    // A board name is followed by its chip, a chip by the boards using it
+    let boards = mcu_to_board_names(&mcu);
+    if let Some(chip) = chip_for_alias(mcu_name) {
+        println_verbose!("Target: {} ({})", mcu_name, chip);
+    } else if boards.is_empty() {
+        println_verbose!("Target: {}", mcu_name);
+    } else {
+        println_verbose!("Target: {} ({})", mcu_name, boards.join(", "));
     }
 
+    let timeout = match matches.value_of("timeout") {
+        Some(ms) => match ms.parse() {
+            Ok(ms) => Some(ms),
+            Err(_) => {
+                eprintln!("Invalid timeout \"{}\"", ms);
+                exit(1);
+            }
+        },
+        None => config.timeout,
+    }
+    .map(Duration::from_millis);
+
+    let mut connect_options = ConnectOptions {
+        retry_interval: matches
+            .value_of("retry-interval")
+            .map(|ms| match ms.parse() {
+                Ok(ms) => Duration::from_millis(ms),
+                Err(_) => {
+                    eprintln!("Invalid retry interval \"{}\"", ms);
+                    exit(1);
+                }
+            }),
+        path: match (matches.value_of("bus"), matches.value_of("address")) {
+            (Some(bus), Some(address)) => match (bus.parse(), address.parse()) {
+                (Ok(bus), Ok(address)) => Some(UsbPath { bus, address }),
+                _ => {
+                    eprintln!("Invalid USB bus \"{}\" or address \"{}\"", bus, address);
+                    exit(1);
+                }
+            },
+            _ => None,
+        },
+        sync: matches.is_present("win-sync") || std::env::var_os(WIN_SYNC_ENV).is_some(),
+        ..Default::default()
+    };
+
+    // --timeouts over TEENSY_TIMEOUTS, each phase on its own
+    let timeouts = {
+        let parse = |source: &str, s: &str| match TimeoutConfig::parse(s) {
+            Ok(timeouts) => timeouts,
+            Err(err) => {
+                match err {
+                    TimeoutsError::Malformed(entry) => {
+                        eprintln!("Invalid {} entry \"{}\", expected key=ms", source, entry)
+                    }
+                    TimeoutsError::UnknownKey(key) => eprintln!(
+                        "Unknown {} key \"{}\", expected one of {}",
+                        source,
+                        key,
+                        TIMEOUT_KEYS.join(", ")
+                    ),
+                    TimeoutsError::InvalidValue(key, value) => eprintln!(
+                        "Invalid {} value \"{}\" for {}, expected milliseconds",
+                        source, value, key
+                    ),
+                }
+                exit(1);
+            }
+        };
+        let flag = matches
+            .value_of("timeouts")
+            .map(|s| parse("--timeouts", s))
+            .unwrap_or_default();
+        let env = std::env::var(TIMEOUTS_ENV)
+            .ok()
+            .map(|s| parse(TIMEOUTS_ENV, &s))
+            .unwrap_or_default();
+        flag.or(env)
+    };
+
+    let settle = match (matches.occurrences_of("settle"), timeouts.settle) {
+        // Only an explicit --settle beats the timeouts, not its default
+        (0, Some(settle)) => settle,
+        _ => match matches.value_of("settle").unwrap().parse() {
+            Ok(ms) => Duration::from_millis(ms),
+            Err(_) => {
+                eprintln!(
+                    "Invalid settle time \"{}\"",
+                    matches.value_of("settle").unwrap()
+                );
+                exit(1);
+            }
+        },
+    };
+
     let boot_only = matches.is_present("boot-only");
+    let erase_only = matches.is_present("erase");
+    let check_only = matches.is_present("check-only");
+
+    if json {
+        summary::start(Summary {
+            file: None,
+            detected_type: None,
+            bytes: None,
+            code_size: mcu.code_size,
+            usage_percent: None,
+            crc32: None,
+            result: "ok",
+            exit_code: 0,
+        });
+    }
+
+    let start_addr = matches
+        .value_of("start")
+        .map(|addr| match parse_addr(addr) {
+            Some(addr) => addr as usize,
+            None => {
+                eprintln!("Invalid start address \"{}\"", addr);
+                exit(1);
+            }
+        });
+
+    let block_delay = match matches.value_of("block-delay").map(str::parse) {
+        Some(Ok(ms)) => Duration::from_millis(ms),
+        Some(Err(_)) => {
+            eprintln!(
+                "Invalid block delay \"{}\"",
+                matches.value_of("block-delay").unwrap()
+            );
+            exit(1);
+        }
+        None => Duration::from_millis(0),
+    };
+
+    let fill_byte = {
+        let arg = matches.value_of("fill-byte").unwrap();
+        let hex = arg
+            .strip_prefix("0x")
+            .or_else(|| arg.strip_prefix("0X"))
+            .unwrap_or(arg);
+        match u8::from_str_radix(hex, 16) {
+            Ok(byte) => byte,
+            Err(_) => {
+                eprintln!("Invalid fill byte \"{}\"", arg);
+                exit(1);
+            }
+        }
+    };
+
+    let warn_usage: f64 = match matches.value_of("warn-usage").unwrap().parse() {
+        Ok(percent) => percent,
+        Err(_) => {
+            eprintln!(
+                "Invalid usage threshold \"{}\"",
+                matches.value_of("warn-usage").unwrap()
+            );
+            exit(1);
+        }
+    };
 
-    let binary = if !boot_only {
-        let file_path = matches
-            .value_of("file")
-            .expect("No file path though boot-only not set");
+    if let Some(diff) = matches.subcommand_matches("diff") {
+        let options = LoadOptions {
+            fill_byte: Some(fill_byte),
+            ..Default::default()
+        };
+        let load = |file_path| match load_file_with(file_path, FileHint::Any, &mcu, &options) {
+            Ok((binary, _, _)) => binary,
+            Err(err) => {
+                report_load_error(file_path, FileHint::Any, mcu_name, &mcu, err);
+                exit(EXIT_FILE);
+            }
+        };
+        let old = load(diff.value_of("old").unwrap());
+        let new = load(diff.value_of("new").unwrap());
+
+        let ranges = diff_images(&old, &new, mcu.block_size);
+        if ranges.is_empty() {
+            println!("Flash images are identical");
+        }
+        for range in &ranges {
+            println!(
+                "{:#08x}..{:#08x} ({} blocks)",
+                range.start,
+                range.end,
+                range.len() / mcu.block_size
+            );
+        }
+        let blocks: usize = ranges.iter().map(|r| r.len() / mcu.block_size).sum();
+        println_verbose!(
+            "{} of {} blocks differ",
+            blocks,
+            mcu.code_size / mcu.block_size
+        );
+        return;
+    }
+
+    let binary = if !boot_only && !erase_only {
+        let file_path = match config.resolve_file(matches.value_of("file"), env_file.as_deref()) {
+            Some(path) => path,
+            None => {
+                eprintln!(
+                    "No file given (hint: pass a path, set TEENSY_FIRMWARE or set `file` in .teensy.toml)"
+                );
+                exit(1);
+            }
+        };
         let file_hint = match (matches.is_present("ihex"), matches.is_present("elf")) {
             (true, false) => FileHint::IHEX,
             (false, true) => FileHint::ELF,
             _ => FileHint::Any,
         };
-        match load_file(file_path, file_hint, &mcu) {
-            Ok((binary, len)) => {
+        let base = matches.value_of("base").map(|addr| match parse_addr(addr) {
+            Some(addr) => addr,
+            None => {
+                eprintln!("Invalid base address \"{}\"", addr);
+                exit(1);
+            }
+        });
+        if matches.is_present("dump-records") {
+            let data = match std::fs::read(file_path) {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("Failed to open \"{}\"", file_path);
+                    println_verbose!("Error: {}", err);
+                    exit(EXIT_FILE);
+                }
+            };
+            // Only fails once stdout is closed, as when piped into head, and then nobody's reading
+            let _ = dump_records(&data, file_hint, &mut std::io::stdout());
+            return;
+        }
+        let load_options = LoadOptions {
+            base,
+            fill_byte: Some(fill_byte),
+        };
+        // A build racing with us can rewrite the file while it is being read
+        let stamp = file_stamp(file_path);
+        match load_file_with(file_path, file_hint, &mcu, &load_options) {
+            Ok(_) if stamp.is_some() && file_stamp(file_path) != stamp => {
+                eprintln!(
+                    "\"{}\" changed while it was being read, try again once it is written",
+                    file_path
+                );
+                exit(EXIT_FILE);
+            }
+            Ok((binary, touched, file_type)) => {
+                let written = touched.written();
+                let usage = usage(&touched, &mcu);
+                println_verbose!("Detected: {}", file_type.to_str());
                 println_verbose!(
-                    "Read \"{}\": {} bytes, {:.*}% usage",
+                    "Read \"{}\": {} bytes written, {:.*}% of flash spanned",
                     file_path,
-                    len,
+                    written,
                     1,
-                    len as f64 / mcu.code_size as f64 * 100.0
+                    usage
                 );
 
-                Some(binary)
-            }
-            Err(err) => {
-                match err {
-                    LoadError::FailedOpen(err) => {
-                        eprintln!("Failed to open \"{}\"", file_path);
-                        println_verbose!("Error: {}", err);
-                    }
-                    LoadError::FailedRead(err) => {
-                        eprintln!("Failed to read \"{:?}\"", file_path);
-                        println_verbose!("Error: {}", err);
-                    }
-                    LoadError::NotValidFile => {
+                summary::update(|summary| {
+                    summary.file = Some(file_path.to_string());
+                    summary.detected_type = Some(file_type.to_str());
+                    summary.bytes = Some(written);
+                    summary.usage_percent = Some(usage);
+                    summary.crc32 = Some(crc32(&binary));
+                });
+
+                let used = touched.span().map_or(0, |span| span.len());
+                if usage > 100.0 {
+                    eprintln!(
+                        "\"{}\" doesn't fit in {}'s flash ({} of {} bytes)",
+                        file_path, mcu_name, used, mcu.code_size
+                    );
+                    exit(EXIT_FILE);
+                } else if usage > warn_usage {
+                    warn!(
+                        "\"{}\" uses {:.1}% of {}'s flash ({} of {} bytes)",
+                        file_path, usage, mcu_name, used, mcu.code_size
+                    );
+                }
+
+                if let (Some(start_addr), Some(span)) = (start_addr, touched.span()) {
+                    if span.start < start_addr {
                         eprintln!(
-                            "\"{}\" does not seem to be an {} file",
-                            file_path,
-                            file_hint.to_str(),
+                            "\"{}\" has data at {:#x}, below the start address {:#x}",
+                            file_path, span.start, start_addr
                         );
+                        exit(EXIT_FILE);
                     }
                 }
-                std::process::exit(1);
+
+                // Programming a region leaves the vector table that's already there alone
+                if start_addr.unwrap_or(0) == 0 {
+                    match check_vector_table(&binary, &mcu) {
+                        Some(VectorTableProblem::BadStackPointer(sp)) => warn!(
+                            "\"{}\" has an invalid initial stack pointer {:#010x}, the board will likely hang",
+                            file_path, sp
+                        ),
+                        Some(VectorTableProblem::BadResetVector(pc)) => warn!(
+                            "\"{}\" has an invalid reset vector {:#010x}, the board will likely hang",
+                            file_path, pc
+                        ),
+                        None => {}
+                    }
+                }
+
+                Some(binary)
+            }
+            Err(err) => {
+                report_load_error(file_path, file_hint, mcu_name, &mcu, err);
+                exit(EXIT_FILE);
             }
         }
     } else {
         None
     };
 
-    let wait_for_device = matches.is_present("wait");
-    let mut waited = false;
-    let mut teensy = loop {
-        match Teensy::connect(mcu) {
-            Ok(t) => break t,
-            Err(err) => {
-                if err == ConnectError::DeviceNotFound && !wait_for_device {
-                    eprintln!("Unable to open device (hint: try --wait)");
-                    std::process::exit(1);
-                } else if err != ConnectError::DeviceNotFound {
-                    println_verbose!("Connection error: {:?}", err);
-                    std::process::exit(1);
-                }
+    // Everything needed to tell whether the image can be programmed is known by now, so check it
+    // before asking the user to plug in or reset a board
+    if let Some(binary) = &binary {
+        if binary.len() > mcu.code_size || binary.len() % mcu.block_size != 0 {
+            eprintln!(
+                "The image is {} bytes, which doesn't fit {}'s {} bytes of flash in {} byte blocks",
+                binary.len(),
+                mcu_name,
+                mcu.code_size,
+                mcu.block_size
+            );
+            exit(EXIT_FILE);
+        }
+        if let Some(start_addr) = start_addr {
+            if start_addr >= binary.len() {
+                eprintln!("Start address is past the end of flash");
+                exit(1);
+            }
+            if start_addr % mcu.block_size != 0 {
+                eprintln!("Start address {:#x} is not on a block boundary", start_addr);
+                exit(1);
             }
         }
-        if !waited {
-            println_verbose!("Waiting for device...");
-            println_verbose!(" (hint: press the reset button)");
-            waited = true;
+    }
+
+    if matches.is_present("dry-run") {
+        println_verbose!("Dry run, not programming");
+        summary::finish("dry-run");
+        return;
+    }
+
+    // With --wait (or --timeout) keep trying until the device shows up, with --retry only so many
+    // times. When both are given whichever limit is reached first ends the wait.
+    let wait_for_device = matches.is_present("wait") || timeout.is_some();
+    let rebooted = matches.value_of("reboot-method") == Some("hid") && reboot_application();
+    let retries = match matches.value_of("retry").map(str::parse::<u32>) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            eprintln!(
+                "Invalid retry count \"{}\"",
+                matches.value_of("retry").unwrap()
+            );
+            exit(1);
+        }
+        None if wait_for_device => None,
+        // Give the bootloader a few seconds to show up after the sketch lets go
+        None if rebooted => Some(REBOOT_RETRIES),
+        None => Some(0),
+    };
+    let retry_delay = match matches.value_of("retry-delay").unwrap().parse() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(_) => {
+            eprintln!(
+                "Invalid retry delay \"{}\"",
+                matches.value_of("retry-delay").unwrap()
+            );
+            exit(1);
         }
-        sleep(Duration::from_millis(250));
     };
 
-    println_verbose!("Found HalfKey Bootloader");
+    if matches.is_present("select") {
+        connect_options.path = Some(select_device(retry_delay, timeout));
+    }
 
-    if !boot_only {
-        if let Some(binary) = binary {
-            println_verbose!("Programming");
+    let result = flash_boards(Job {
+        mcu,
+        mcu_name,
+        connect_options,
+        retries,
+        timeout,
+        retry_delay,
+        binary,
+        start_addr,
+        block_delay,
+        timeouts,
+        settle,
+        continue_on_error: matches.is_present("continue-on-error"),
+        boot_only,
+        erase_only,
+        check_only,
+        no_reboot: matches.is_present("no-reboot"),
+        repeat: matches.is_present("repeat"),
+        quiet: matches.is_present("quiet"),
+        json,
+        cancel,
+    });
+    summary::finish(result);
+}
 
-            if let Err(err) = teensy.program(&binary, |_| print_verbose!(".")) {
-                match err {
-                    ProgramError::BinaryRemainder => {
-                        panic!("Somehow the addressed binary had a remainder")
+/// What to do with each board, worked out from the arguments by `main`.
+struct Job<'a> {
+    mcu: Mcu,
+    mcu_name: &'a str,
+    connect_options: ConnectOptions,
+    /// Connection attempts for the first board, `None` to wait for as long as it takes.
+    retries: Option<u32>,
+    timeout: Option<Duration>,
+    retry_delay: Duration,
+    /// Image to program, `None` when only erasing or booting.
+    binary: Option<Vec<u8>>,
+    start_addr: Option<usize>,
+    block_delay: Duration,
+    timeouts: TimeoutConfig,
+    settle: Duration,
+    continue_on_error: bool,
+    boot_only: bool,
+    erase_only: bool,
+    check_only: bool,
+    no_reboot: bool,
+    repeat: bool,
+    quiet: bool,
+    json: bool,
+    /// Set by the Ctrl-C handler along with `INTERRUPTED`, for `ProgramOptions::cancel`.
+    cancel: Arc<AtomicBool>,
+}
+
+/// Waits for a board, then erases, programs and boots it as `job` says, and with `--repeat` does
+/// the same for every board plugged in after it until interrupted.
+///
+/// Returns the result to put in the JSON summary, errors exit from here.
+fn flash_boards(job: Job) -> &'static str {
+    let Job {
+        mcu,
+        mcu_name,
+        connect_options,
+        retries,
+        timeout,
+        retry_delay,
+        binary,
+        start_addr,
+        block_delay,
+        timeouts,
+        settle,
+        continue_on_error,
+        boot_only,
+        erase_only,
+        check_only,
+        no_reboot,
+        repeat,
+        quiet,
+        json,
+        cancel,
+    } = job;
+
+    let mut flashed = 0;
+    loop {
+        // Only the first board is subject to --retry and --timeout, after that we wait for as long
+        // as it takes for the next one to be plugged in
+        let (retries, timeout) = if flashed == 0 {
+            (retries, timeout)
+        } else {
+            (None, None)
+        };
+        let wait_start = Instant::now();
+        let mut attempts = 0;
+        let mut waited = false;
+        let mut hinted_reset = false;
+        // On a terminal this replaces the waiting message, so it's clear we haven't hung
+        let mut waiting = Spinner::new(
+            "Waiting for device (hint: press the reset button)",
+            unsafe { VERBOSE },
+        );
+        let mut teensy = loop {
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                waiting.clear();
+                if repeat {
+                    println_verbose!();
+                    if !quiet {
+                        println!("Flashed {} boards", flashed);
                     }
-                    ProgramError::UnknownBlockSize(size) => {
-                        eprintln!("Unknown block size");
-                        println_verbose!("block: {}", size);
-                        std::process::exit(1);
+                    return "ok";
+                }
+                eprintln!("Aborted by user");
+                exit(EXIT_INTERRUPTED);
+            }
+            let err = match Teensy::connect_with(mcu, &connect_options) {
+                Ok(t) => break t,
+                Err(err @ ConnectError::DeviceNotFound)
+                | Err(err @ ConnectError::FoundButNotInBootloader) => err,
+                Err(err) => {
+                    waiting.clear();
+                    match err {
+                        ConnectError::PathUnsupported => {
+                            eprintln!("--bus and --address need the libusb backend");
+                        }
+                        ConnectError::UnsupportedBlockSize(size) => {
+                            eprintln!("Unsupported block size {} for {}", size, mcu_name);
+                        }
+                        err => println_verbose!("Connection error: {:?}", err),
                     }
-                    ProgramError::WriteError(err) => {
-                        eprintln!("Error writing to Teensy");
-                        println_verbose!("Error: {:?}", err);
-                        std::process::exit(1);
+                    exit(1);
+                }
+            };
+
+            if retries.is_some_and(|retries| attempts >= retries) {
+                waiting.clear();
+                // A board that is already running needs no booting, so waiting for one is no help
+                if boot_only && err == ConnectError::FoundButNotInBootloader {
+                    eprintln!("The board is already running, there is nothing to boot");
+                } else if boot_only && attempts == 0 {
+                    eprintln!("No board in bootloader mode to boot");
+                } else if err == ConnectError::FoundButNotInBootloader {
+                    eprintln!("Device is not in the bootloader (hint: press the reset button)");
+                } else if attempts == 0 {
+                    eprintln!("Unable to open device (hint: try --wait)");
+                } else {
+                    eprintln!("Unable to open device after {} attempts", attempts + 1);
+                }
+                exit(EXIT_NO_DEVICE);
+            }
+            if let Some(timeout) = timeout {
+                if wait_start.elapsed() >= timeout {
+                    waiting.clear();
+                    eprintln!("Timed out waiting for device");
+                    exit(EXIT_NO_DEVICE);
+                }
+            }
+            attempts += 1;
+
+            if err == ConnectError::FoundButNotInBootloader && !hinted_reset {
+                waiting.clear();
+                println_verbose!("Found device running an application, press the reset button");
+                hinted_reset = true;
+            }
+            if !waited && !waiting.is_active() {
+                println_verbose!("Waiting for device...");
+                println_verbose!(" (hint: press the reset button)");
+            }
+            waited = true;
+            waiting.spin_for(retry_delay);
+        };
+        waiting.clear();
+
+        println_verbose!("Found HalfKey Bootloader");
+
+        match teensy.detect_block_size() {
+            Ok(block_size) if block_size != mcu.block_size && check_only => {
+                eprintln!(
+                    "The device uses {} byte blocks but {} uses {} byte blocks",
+                    block_size, mcu_name, mcu.block_size
+                );
+                exit(1);
+            }
+            Ok(block_size) if block_size != mcu.block_size => warn!(
+                "The device uses {} byte blocks but {} uses {} byte blocks, \
+                 flashing will corrupt the image if --mcu is wrong",
+                block_size, mcu_name, mcu.block_size
+            ),
+            Ok(_) => {}
+            Err(err) if check_only => {
+                eprintln!("Unable to detect the device's block size");
+                println_verbose!("Error: {:?}", err);
+                exit(1);
+            }
+            Err(err) => debug!("Unable to detect block size: {:?}", err),
+        }
+
+        // The image was checked against the MCU before connecting, so this is as far as a check
+        // goes without writing
+        if check_only {
+            println_verbose!("Check passed, not programming");
+            return "check-only";
+        }
+
+        if erase_only {
+            println_verbose!("Erasing");
+            let timeout = timeouts
+                .first
+                .unwrap_or_else(|| erase_timeout(mcu.code_size));
+            if let Err(err) = teensy.erase_with_timeout(timeout) {
+                eprintln!("Erase failed");
+                println_verbose!("Erase error: {:?}", err);
+                exit(EXIT_WRITE);
+            }
+        }
+
+        // Bytes sent and how long that took, reported once the board has booted
+        let mut transfer = None;
+        if !boot_only {
+            if let Some(binary) = &binary {
+                println_verbose!("Programming");
+
+                // Block being written, to say where a failed write left off
+                let writing = Cell::new(None);
+                let feedback = |progress: &Progress| {
+                    writing.set(Some(progress.addr));
+                    print_verbose!(".");
+                };
+                let options = ProgramOptions {
+                    continue_on_error,
+                    inter_block_delay: block_delay,
+                    erase_timeout: timeouts.first,
+                    block_timeout: timeouts.block,
+                    cancel: Some(Arc::clone(&cancel)),
+                    ..Default::default()
+                };
+                let res = match start_addr {
+                    Some(start_addr) if start_addr < binary.len() => {
+                        teensy.program_region(&binary[start_addr..], start_addr, &options, feedback)
                     }
+                    Some(_) => Err(ProgramError::RegionTooLarge),
+                    None => teensy.program_with(binary, &options, feedback),
+                };
+                let report = match res {
+                    Ok(report) => report,
+                    Err(err) => match err {
+                        ProgramError::BinaryRemainder => {
+                            panic!("Somehow the addressed binary had a remainder")
+                        }
+                        ProgramError::EmptyImage => {
+                            eprintln!("Nothing to program, the image is empty");
+                            exit(1);
+                        }
+                        ProgramError::UnalignedStart(addr) => {
+                            eprintln!("Start address {:#x} is not on a block boundary", addr);
+                            exit(1);
+                        }
+                        ProgramError::RegionTooLarge => {
+                            eprintln!("Start address is past the end of flash");
+                            exit(1);
+                        }
+                        ProgramError::UnknownBlockSize(size) => {
+                            eprintln!("Unknown block size");
+                            println_verbose!("block: {}", size);
+                            exit(1);
+                        }
+                        ProgramError::ReconnectFailed(err) => {
+                            eprintln!(
+                                "The device went away after the first block and didn't come back"
+                            );
+                            println_verbose!("Error: {:?}", err);
+                            print_recovery_hint();
+                            exit(EXIT_WRITE);
+                        }
+                        ProgramError::Disconnected { last_addr } => {
+                            match last_addr {
+                                Some(addr) => {
+                                    eprintln!(
+                                        "The device disconnected after writing {:#x}, \
+                                         it may have reset",
+                                        addr
+                                    );
+                                    print_recovery_hint();
+                                }
+                                None => {
+                                    eprintln!(
+                                        "The device disconnected before anything was written, \
+                                         it may have reset"
+                                    );
+                                    eprintln!("Run again with --wait to wait for it to come back");
+                                }
+                            }
+                            exit(EXIT_WRITE);
+                        }
+                        ProgramError::Stalled { last_addr } => {
+                            eprintln!("Programming stalled after writing {:#x}", last_addr);
+                            print_recovery_hint();
+                            exit(EXIT_WRITE);
+                        }
+                        ProgramError::DiffLengthMismatch => {
+                            panic!("Somehow program_diff was called")
+                        }
+                        ProgramError::ReadError(_) => {
+                            panic!("Somehow program_reader was called")
+                        }
+                        ProgramError::Cancelled { .. } => {
+                            println_verbose!();
+                            eprintln!(
+                                "Aborted by user; board may be in an inconsistent state, re-flash to recover"
+                            );
+                            // Release the interface and give the device back to its driver first
+                            drop(teensy);
+                            exit(EXIT_INTERRUPTED);
+                        }
+                        ProgramError::PartialFailure(failures) => {
+                            println_verbose!();
+                            eprintln!("{} blocks failed to write:", failures.len());
+                            for (addr, err) in failures {
+                                eprintln!("  {:#08x}: {:?}", addr, err);
+                            }
+                            print_recovery_hint();
+                            exit(EXIT_WRITE);
+                        }
+                        ProgramError::WriteError(err) => {
+                            match writing.get() {
+                                Some(addr) => {
+                                    eprintln!("Error writing the block at {:#x} to Teensy", addr)
+                                }
+                                None => eprintln!("Error writing to Teensy"),
+                            }
+                            println_verbose!("Error: {:?}", err);
+                            print_recovery_hint();
+                            exit(EXIT_WRITE);
+                        }
+                    },
+                };
+
+                println_verbose!();
+                println_verbose!(
+                    "Wrote {} of {} blocks (skipped {} blank)",
+                    report.blocks_written,
+                    report.blocks_written + report.blocks_skipped,
+                    report.blocks_skipped
+                );
+                println_verbose!("CRC-32 of the blocks written: {:#010x}", report.crc32);
+                if report.max_retries_on_any_block >= MARGINAL_LINK_RETRIES {
+                    warn!(
+                        "A block took {} retries to be accepted, the USB connection may be \
+                         unreliable (try another cable, port or hub)",
+                        report.max_retries_on_any_block
+                    );
                 }
+                transfer = Some((report.blocks_written * mcu.block_size, report.elapsed));
+            }
+        }
+
+        if !no_reboot || boot_only {
+            // Some boards reject the boot command if it follows the last block too closely
+            if !boot_only && settle > Duration::from_millis(0) {
+                println_verbose!("Settling for {:?}", settle);
+                sleep(settle);
             }
 
-            println_verbose!();
+            println_verbose!("Booting");
+            let boot_timeout = timeouts.boot.unwrap_or(DEFAULT_BLOCK_TIMEOUT);
+            if let Err(err) = teensy.boot_with_timeout(boot_timeout) {
+                eprintln!("Boot failed");
+                println_verbose!("Boot error: {:?}", err);
+                exit(EXIT_WRITE);
+            }
+        }
+
+        // Nothing written, or too quick to time, would only make for an inf or NaN rate
+        if let Some((bytes, elapsed)) = transfer.filter(|&(bytes, elapsed)| {
+            !quiet && !json && bytes > 0 && elapsed > Duration::from_millis(0)
+        }) {
+            println!(
+                "Programmed {} bytes in {:.2}s ({:.1} KiB/s)",
+                bytes,
+                elapsed.as_secs_f64(),
+                bytes as f64 / 1024.0 / elapsed.as_secs_f64()
+            );
+        }
+
+        if !repeat {
+            break;
+        }
+        flashed += 1;
+        if !quiet {
+            println!("Flashed board {}", flashed);
+        }
+
+        // Don't pick the same board up again before it has left the bootloader
+        println_verbose!("Waiting for the board to be unplugged or reboot");
+        while teensy.is_connected() && !INTERRUPTED.load(Ordering::SeqCst) {
+            sleep(retry_delay);
         }
     }
 
-    if !matches.is_present("no-reboot") || boot_only {
-        println_verbose!("Booting");
-        if let Err(err) = teensy.boot() {
-            eprintln!("Boot failed");
-            println_verbose!("Boot error: {:?}", err);
-            std::process::exit(1);
+    "ok"
+}
+
+/// Exits with `code`, printing the JSON summary first with `--format json` so scripts get one
+/// however the run ended.
+fn exit(code: i32) -> ! {
+    summary::fail(code);
+    std::process::exit(code)
+}
+
+/// Loads the same program as an ELF and as Intel hex, from copies of the test fixtures built into
+/// the binary, and prints whether they produced the same image.
+///
+/// A failure here means the loader itself is broken in this build, rather than the user's file.
+fn self_test() -> bool {
+    const ELF: &[u8] = include_bytes!("../tests/blink");
+    const IHEX: &[u8] = include_bytes!("../tests/blink.ihex");
+    const MCU: Mcu = rusty_loader::mcu!("TEENSYLC");
+
+    let options = LoadOptions::default();
+    let passed = match (
+        parse_image(ELF, FileHint::ELF, &MCU, &options),
+        parse_image(IHEX, FileHint::IHEX, &MCU, &options),
+    ) {
+        (Ok(elf), Ok(ihex)) => elf.0 == ihex.0 && elf.1 == ihex.1,
+        (elf, ihex) => {
+            println_verbose!("ELF: {:?}", elf.map(|(_, touched, _)| touched.written()));
+            println_verbose!(
+                "Intel hex: {:?}",
+                ihex.map(|(_, touched, _)| touched.written())
+            );
+            false
+        }
+    };
+    println!("{}", if passed { "PASS" } else { "FAIL" });
+    passed
+}
+
+/// Waits until at least one device is in the bootloader, then asks on the terminal which of the
+/// ones there to use. Takes the first without asking when there is only one, or stdin isn't a
+/// terminal to ask on.
+///
+/// The list and prompt go to stderr, leaving stdout to the JSON summary.
+fn select_device(retry_delay: Duration, timeout: Option<Duration>) -> UsbPath {
+    let wait_start = Instant::now();
+    let mut waiting = Spinner::new(
+        "Waiting for devices (hint: press the reset buttons)",
+        unsafe { VERBOSE },
+    );
+    let paths = loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            waiting.clear();
+            eprintln!("Aborted by user");
+            exit(EXIT_INTERRUPTED);
+        }
+        match Teensy::list() {
+            Ok(paths) if !paths.is_empty() => break paths,
+            Ok(_) => {}
+            Err(ConnectError::PathUnsupported) => {
+                waiting.clear();
+                eprintln!("--select needs the libusb backend");
+                exit(1);
+            }
+            Err(err) => {
+                waiting.clear();
+                eprintln!("Unable to list devices: {:?}", err);
+                exit(1);
+            }
+        }
+        if timeout.is_some_and(|timeout| wait_start.elapsed() >= timeout) {
+            waiting.clear();
+            eprintln!("Timed out waiting for device");
+            exit(EXIT_NO_DEVICE);
         }
+        waiting.spin_for(retry_delay);
+    };
+    waiting.clear();
+
+    if paths.len() == 1 || !io::stdin().is_terminal() {
+        println_verbose!(
+            "Using the bootloader on bus {} at address {}",
+            paths[0].bus,
+            paths[0].address
+        );
+        return paths[0];
+    }
+    for (i, path) in paths.iter().enumerate() {
+        eprintln!("{}: bus {} address {}", i, path.bus, path.address);
+    }
+    loop {
+        eprint!("Program which one? [0-{}] ", paths.len() - 1);
+        let _ = io::stderr().flush();
+        let mut line = String::new();
+        // Ctrl-C doesn't interrupt the read, so check once the line is in
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 || INTERRUPTED.load(Ordering::SeqCst)
+        {
+            eprintln!();
+            eprintln!("Aborted by user");
+            exit(EXIT_INTERRUPTED);
+        }
+        if let Some(&path) = line.trim().parse::<usize>().ok().and_then(|i| paths.get(i)) {
+            return path;
+        }
+    }
+}
+
+/// Explains why `file_path` couldn't be loaded for `mcu`.
+fn report_load_error(
+    file_path: &str,
+    file_hint: FileHint,
+    mcu_name: &str,
+    mcu: &Mcu,
+    err: LoadError,
+) {
+    match err {
+        LoadError::FailedOpen(err) => {
+            eprintln!("Failed to open \"{}\"", file_path);
+            println_verbose!("Error: {}", err);
+        }
+        LoadError::FailedRead(err) => {
+            eprintln!("Failed to read \"{:?}\"", file_path);
+            println_verbose!("Error: {}", err);
+        }
+        LoadError::NotValidFile => {
+            eprintln!(
+                "\"{}\" does not seem to be an {} file",
+                file_path,
+                file_hint.to_str(),
+            );
+        }
+        LoadError::NeedsBase => {
+            eprintln!(
+                "\"{}\" is a position independent ELF, use --base to give its load address",
+                file_path
+            );
+        }
+        LoadError::InvalidElf(ElfError::WrongEndianness) => {
+            eprintln!(
+                "\"{}\" is a big-endian ELF, Teensy MCUs are little-endian",
+                file_path
+            );
+        }
+        LoadError::InvalidElf(ElfError::SectionOutsideFlash { name, addr }) => {
+            eprintln!(
+                "\"{}\" loads section \"{}\" at {:#x}, outside of flash",
+                file_path, name, addr
+            );
+        }
+        LoadError::InvalidElf(ElfError::OverlappingSections { a, b, addr }) => {
+            eprintln!(
+                "\"{}\" loads sections \"{}\" and \"{}\" over each other at {:#x}, \
+                 check the linker script",
+                file_path, a, b, addr
+            );
+        }
+        LoadError::InvalidElf(ElfError::NoProgramHeaderForSection { name, addr }) => {
+            eprintln!(
+                "\"{}\" has section \"{}\" at {:#x} outside of every segment, \
+                 so where it goes in flash isn't known",
+                file_path, name, addr
+            );
+        }
+        LoadError::InvalidElf(ElfError::WrongMcu(target)) => {
+            eprintln!(
+                "\"{}\" was built for {}, not {}",
+                file_path, target, mcu_name
+            );
+        }
+        LoadError::InvalidElf(err) => {
+            eprintln!("\"{}\" is not a usable ELF file", file_path);
+            println_verbose!("Error: {:?}", err);
+        }
+        LoadError::InvalidIHex(IHexError::AddressTooHigh(end)) => {
+            eprint!(
+                "\"{}\" reaches {:#x} but {} is {} KiB",
+                file_path,
+                end,
+                mcu_name,
+                mcu.code_size / 1024
+            );
+            match smallest_mcu_fitting(mcu, end) {
+                Some((name, fits)) => {
+                    eprintln!("; try {} ({} KiB)", name, fits.code_size / 1024)
+                }
+                None => eprintln!(),
+            }
+        }
+    }
+}
+
+/// Size and modification time of `path`, `None` if either can't be read.
+fn file_stamp(path: &str) -> Option<(u64, SystemTime)> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+/// Explains what to do about a flash that failed partway, which leaves the board unable to run
+/// but easy to recover, as HalfKay itself is never overwritten.
+fn print_recovery_hint() {
+    eprintln!(
+        "The flash is incomplete, so the board won't run properly until it is flashed again."
+    );
+    eprintln!(
+        "It isn't damaged: press the reset button and flash again (--wait waits for the bootloader)."
+    );
+}
+
+/// Asks a running sketch to reboot into the bootloader, returns whether one accepted.
+fn reboot_application() -> bool {
+    for &pid in &SEREMU_PRODUCT_IDS {
+        match Teensy::reboot_application(TEENSY_VENDOR_ID, pid) {
+            Ok(()) => {
+                println_verbose!("Rebooted the running sketch");
+                return true;
+            }
+            Err(RebootError::DeviceNotFound) => {}
+            Err(err) => debug!("failed to reboot {:04x}: {:?}", pid, err),
+        }
+    }
+    false
+}
+
+/// Parses an address given in decimal or, with a `0x` prefix, hex.
+fn parse_addr(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
     }
 }
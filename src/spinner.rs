@@ -0,0 +1,75 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+/// How often the spinner moves on, however often it is ticked.
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A message with a spinning bar after it, redrawn in place on stdout to show we're still alive
+/// while waiting on something that may take a while.
+///
+/// Only draws when enabled and stdout is a terminal, so piped output never sees the carriage
+/// returns. `is_active` says whether it is drawing, for callers that print something else
+/// instead.
+pub struct Spinner {
+    message: String,
+    active: bool,
+    frame: usize,
+    last_draw: Option<Instant>,
+}
+
+impl Spinner {
+    pub fn new(message: impl Into<String>, enabled: bool) -> Self {
+        Spinner {
+            message: message.into(),
+            active: enabled && io::stdout().is_terminal(),
+            frame: 0,
+            last_draw: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Moves the spinner on if it hasn't for a frame.
+    pub fn tick(&mut self) {
+        if !self.active || self.last_draw.is_some_and(|t| t.elapsed() < FRAME_INTERVAL) {
+            return;
+        }
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\r{} {}", self.message, FRAMES[self.frame]);
+        let _ = stdout.flush();
+        self.frame = (self.frame + 1) % FRAMES.len();
+        self.last_draw = Some(Instant::now());
+    }
+
+    /// Ticks for `duration`, for use in place of `sleep`.
+    pub fn spin_for(&mut self, duration: Duration) {
+        let end = Instant::now() + duration;
+        loop {
+            self.tick();
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            std::thread::sleep(FRAME_INTERVAL.min(end - now));
+        }
+    }
+
+    /// Erases the spinner's line, ready for other output. The next `tick` draws it again.
+    pub fn clear(&mut self) {
+        if self.last_draw.take().is_none() {
+            return;
+        }
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\r{:width$}\r", "", width = self.message.len() + 2);
+        let _ = stdout.flush();
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// Machine readable description of a run, printed with `--format json`.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub file: Option<String>,
+    pub detected_type: Option<&'static str>,
+    /// Number of bytes the file provided
+    pub bytes: Option<usize>,
+    pub code_size: usize,
+    /// Percentage of flash spanned by the image, including gaps
+    pub usage_percent: Option<f64>,
+    /// CRC-32 of the whole flash image, including the fill of unused flash
+    pub crc32: Option<u32>,
+    pub result: &'static str,
+    pub exit_code: i32,
+}
+
+/// The summary of this run once `start` has been called, which is only done with `--format json`.
+///
+/// It lives here rather than in `main` so that every way out, including the many error exits,
+/// can print it.
+static SUMMARY: Mutex<Option<Summary>> = Mutex::new(None);
+
+/// Starts collecting `summary`, to be printed by `finish` or `fail`.
+pub fn start(summary: Summary) {
+    *SUMMARY.lock().unwrap() = Some(summary);
+}
+
+/// Fills in more of the summary, if one is being collected.
+pub fn update(f: impl FnOnce(&mut Summary)) {
+    if let Some(summary) = SUMMARY.lock().unwrap().as_mut() {
+        f(summary);
+    }
+}
+
+/// Prints the summary of a successful run, if one is being collected.
+pub fn finish(result: &'static str) {
+    if let Some(summary) = SUMMARY.lock().unwrap().as_mut() {
+        summary.result = result;
+        print_json(summary);
+    }
+}
+
+/// Prints the summary of a run ending with `exit_code`, if one is being collected.
+pub fn fail(exit_code: i32) {
+    if let Some(summary) = SUMMARY.lock().unwrap().as_mut() {
+        summary.result = "error";
+        summary.exit_code = exit_code;
+        print_json(summary);
+    }
+}
+
+#[cfg(feature = "json")]
+fn print_json(summary: &Summary) {
+    match serde_json::to_string(summary) {
+        Ok(json) => println!("{}", json),
+        Err(err) => eprintln!("Failed to serialize summary: {}", err),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn print_json(_summary: &Summary) {
+    unreachable!("--format json is rejected without the json feature")
+}
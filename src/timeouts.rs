@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+/// Environment variable holding a timing profile in the same form as `--timeouts`, used for any
+/// phase the flag doesn't give.
+pub const TIMEOUTS_ENV: &str = "TEENSY_TIMEOUTS";
+
+/// Timings for each phase of a flash, given together as `first=8000,block=500,...` in
+/// milliseconds.
+///
+/// Every phase is optional, those not given keep their usual value.
+#[derive(Debug, Default, PartialEq)]
+pub struct TimeoutConfig {
+    /// Timeout for the first block, which HalfKay only accepts once it has erased the chip.
+    pub first: Option<Duration>,
+    /// Timeout for every other block.
+    pub block: Option<Duration>,
+    /// Timeout for the boot command.
+    pub boot: Option<Duration>,
+    /// Pause between the last block and the boot command.
+    pub settle: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TimeoutsError {
+    /// An entry that isn't `key=value`.
+    Malformed(String),
+    UnknownKey(String),
+    /// A key and the value given for it, which isn't a whole number of milliseconds.
+    InvalidValue(String, String),
+}
+
+/// The keys `TimeoutConfig::parse` accepts.
+pub const TIMEOUT_KEYS: [&str; 4] = ["first", "block", "boot", "settle"];
+
+impl TimeoutConfig {
+    /// Parses a comma separated list of `key=ms` entries, later entries overriding earlier ones.
+    pub fn parse(s: &str) -> Result<Self, TimeoutsError> {
+        let mut config = TimeoutConfig::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| TimeoutsError::Malformed(entry.to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+            let slot = match key {
+                "first" => &mut config.first,
+                "block" => &mut config.block,
+                "boot" => &mut config.boot,
+                "settle" => &mut config.settle,
+                _ => return Err(TimeoutsError::UnknownKey(key.to_string())),
+            };
+            let ms = value
+                .parse()
+                .map_err(|_| TimeoutsError::InvalidValue(key.to_string(), value.to_string()))?;
+            *slot = Some(Duration::from_millis(ms));
+        }
+        Ok(config)
+    }
+
+    /// Each timing from `self`, or from `other` where `self` doesn't give it.
+    pub fn or(self, other: Self) -> Self {
+        TimeoutConfig {
+            first: self.first.or(other.first),
+            block: self.block.or(other.block),
+            boot: self.boot.or(other.boot),
+            settle: self.settle.or(other.settle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_every_key() {
+        let config = TimeoutConfig::parse(" first=8000, block = 500,boot=1000,settle=0,").unwrap();
+        assert_eq!(
+            config,
+            TimeoutConfig {
+                first: Some(Duration::from_millis(8000)),
+                block: Some(Duration::from_millis(500)),
+                boot: Some(Duration::from_millis(1000)),
+                settle: Some(Duration::from_millis(0)),
+            }
+        );
+        assert_eq!(TimeoutConfig::parse(""), Ok(TimeoutConfig::default()));
+    }
+
+    #[test]
+    fn parse_later_entries_win() {
+        let config = TimeoutConfig::parse("block=500,block=250").unwrap();
+        assert_eq!(config.block, Some(Duration::from_millis(250)));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert_eq!(
+            TimeoutConfig::parse("first=8000,block"),
+            Err(TimeoutsError::Malformed("block".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_unknown_key() {
+        assert_eq!(
+            TimeoutConfig::parse("erase=8000"),
+            Err(TimeoutsError::UnknownKey("erase".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_value() {
+        for value in ["", "-1", "1.5", "8s"] {
+            assert_eq!(
+                TimeoutConfig::parse(&format!("boot={}", value)),
+                Err(TimeoutsError::InvalidValue(
+                    "boot".to_string(),
+                    value.to_string()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn or_prefers_self() {
+        let flag = TimeoutConfig::parse("first=8000,block=250").unwrap();
+        let env = TimeoutConfig::parse("block=500,boot=1000").unwrap();
+        assert_eq!(
+            flag.or(env),
+            TimeoutConfig {
+                first: Some(Duration::from_millis(8000)),
+                block: Some(Duration::from_millis(250)),
+                boot: Some(Duration::from_millis(1000)),
+                settle: None,
+            }
+        );
+    }
+}
@@ -1,29 +1,107 @@
-use std::time::Duration;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use crate::Mcu;
+use log::{debug, log_enabled, trace, Level};
 
-#[cfg(all(windows, not(feature = "libusb")))]
+use crate::{Crc32, Mcu, DEFAULT_FILL_BYTE};
+
+#[cfg(all(windows, not(feature = "libusb"), not(test)))]
 mod windows;
-#[cfg(all(windows, not(feature = "libusb")))]
+#[cfg(all(windows, not(feature = "libusb"), not(test)))]
 use windows as sys;
 
-#[cfg(all(all(unix, target_os = "macos"), not(feature = "libusb")))]
+#[cfg(all(all(unix, target_os = "macos"), not(feature = "libusb"), not(test)))]
 mod macos;
-#[cfg(all(all(unix, target_os = "macos"), not(feature = "libusb")))]
+#[cfg(all(all(unix, target_os = "macos"), not(feature = "libusb"), not(test)))]
 use macos as sys;
 
-#[cfg(any(all(unix, not(target_os = "macos")), feature = "libusb"))]
+#[cfg(all(
+    any(all(unix, not(target_os = "macos")), feature = "libusb"),
+    not(test)
+))]
 mod libusb;
-#[cfg(any(all(unix, not(target_os = "macos")), feature = "libusb"))]
+#[cfg(all(
+    any(all(unix, not(target_os = "macos")), feature = "libusb"),
+    not(test)
+))]
 use libusb as sys;
 
-const TEENSY_VENDOR_ID: u16 = 0x16C0;
+#[cfg(test)]
+mod test;
+#[cfg(test)]
+use test as sys;
+
+/// The platform backend's error type, named so other `HalfKayTransport` implementations can
+/// return it.
+pub use sys::SystemError;
+
+pub const TEENSY_VENDOR_ID: u16 = 0x16C0;
 const TEENSY_PRODUCT_ID: u16 = 0x0478;
 
+/// Product IDs a Teensy running a sketch built on the Teensy core shows up with, one per USB type
+/// (Serial, MIDI, Raw HID, ...) as listed in the core's `usb_desc.h`.
+///
+/// The vendor ID is shared with other V-USB and PJRC products, so only these count as a board
+/// waiting to be put into the bootloader.
+pub const TEENSY_APP_PRODUCT_IDS: [u16; 17] = [
+    0x0476, 0x0482, 0x0483, 0x0485, 0x0486, 0x0487, 0x0488, 0x0489, 0x048A, 0x048B, 0x048C, 0x04D0,
+    0x04D1, 0x04D2, 0x04D3, 0x04D4, 0x04D5,
+];
+
+/// Product IDs of the Teensyduino USB types that have no serial port and instead carry a HID
+/// serial emulation interface: Keyboard + Mouse + Joystick, Raw HID and Flight Sim Controls.
+/// Sketches built with these answer `Teensy::reboot_application`.
+pub const SEREMU_PRODUCT_IDS: [u16; 3] = [0x0482, 0x0486, 0x0488];
+
+/// Feature report the Teensy core's serial emulation interface treats as a request to reboot
+/// into the bootloader.
+#[cfg_attr(test, allow(dead_code))]
+pub(crate) const SEREMU_REBOOT_REPORT: [u8; 4] = [0xA9, 0x45, 0xC2, 0x6B];
+
+/// Vendor defined usage page of the Teensy core's serial emulation interface, which tells it apart
+/// from the other HID interfaces of the same USB type.
+#[cfg_attr(test, allow(dead_code))]
+pub(crate) const SEREMU_USAGE_PAGE: u16 = 0xFFC9;
+
+/// How long backends wait between attempts at a write the device hasn't accepted yet.
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long the bootloader gets to accept each block after the first, and the boot command,
+/// unless told otherwise.
+pub const DEFAULT_BLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Environment variable the command line tool reads as `--win-sync`, setting
+/// `ConnectOptions::sync`. The library itself doesn't look at it.
+pub const WIN_SYNC_ENV: &str = "RUSTY_LOADER_WIN_SYNC";
+
+/// The largest block size on the AVR parts, which is how they are told apart from the Kinetis
+/// ones.
+const AVR_MAX_BLOCK_SIZE: usize = 256;
+
+/// How long an AVR part gets to reappear after dropping off the bus during the erase.
+const AVR_RECONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Block sizes used by the HalfKay bootloader across all supported parts.
+pub(crate) const BLOCK_SIZES: [usize; 4] = [128, 256, 512, 1024];
+
 #[derive(Debug, PartialEq)]
 pub enum ConnectError {
     System(sys::SystemError),
     DeviceNotFound,
+    /// A device with the Teensy vendor ID is present, but it is running an application rather
+    /// than the bootloader.
+    FoundButNotInBootloader,
+    /// The `Mcu`'s block size isn't one HalfKay uses, so there is no way to frame its blocks.
+    UnsupportedBlockSize(usize),
+    /// `ConnectOptions::header_size` is too short to hold a block address, anything under 2.
+    UnsupportedHeaderSize(usize),
+    /// `ConnectOptions::path` was given, but this backend can't tell where devices are plugged in.
+    PathUnsupported,
 }
 
 impl From<sys::SystemError> for ConnectError {
@@ -36,6 +114,9 @@ impl From<sys::SystemError> for ConnectError {
 pub enum WriteError {
     System(sys::SystemError),
     Timeout,
+    /// The device is no longer attached, so the handle to it is useless. It may have reset and
+    /// come back as a new device.
+    Disconnected,
 }
 
 impl From<sys::SystemError> for WriteError {
@@ -44,11 +125,76 @@ impl From<sys::SystemError> for WriteError {
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub enum DetectError {
+    System(sys::SystemError),
+    /// The device's output report doesn't match any known block size.
+    UnknownReportLength(usize),
+}
+
+impl From<sys::SystemError> for DetectError {
+    fn from(err: sys::SystemError) -> Self {
+        DetectError::System(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RebootError {
+    System(sys::SystemError),
+    DeviceNotFound,
+    /// The device is there, but none of its HID interfaces accepted the reboot request.
+    Rejected,
+}
+
+impl From<sys::SystemError> for RebootError {
+    fn from(err: sys::SystemError) -> Self {
+        RebootError::System(err)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DumpError {
+    /// The bootloader has no way to read flash back.
+    Unsupported,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ProgramError {
+    /// The image isn't a whole number of blocks.
     BinaryRemainder,
+    EmptyImage,
+    /// The start address given to `Teensy::program_region` isn't on a block boundary.
+    UnalignedStart(usize),
+    /// The region runs past the end of flash.
+    RegionTooLarge,
     UnknownBlockSize(usize),
     WriteError(WriteError),
+    /// The device went away after the first block, as the AVR bootloader does while it erases,
+    /// and couldn't be opened again.
+    ReconnectFailed(ConnectError),
+    /// The device went away in the middle of programming, for instance because it reset.
+    /// `last_addr` is the address of the last block that was written, `None` if it went before
+    /// the first one.
+    Disconnected {
+        last_addr: Option<usize>,
+    },
+    /// Programming took longer than `ProgramOptions::max_duration`. `last_addr` is the address of
+    /// the last block that was written.
+    Stalled {
+        last_addr: usize,
+    },
+    /// Some blocks failed to write with `ProgramOptions::continue_on_error` set. Holds the address
+    /// of each failed block and why it failed.
+    PartialFailure(Vec<(usize, WriteError)>),
+    /// Reading the image given to `Teensy::program_reader` failed, or it ended early.
+    ReadError(io::ErrorKind),
+    /// The two images given to `Teensy::program_diff` aren't the same length.
+    DiffLengthMismatch,
+    /// `ProgramOptions::cancel` was set. `last_addr` is the address of the last block that was
+    /// written, `None` if it was set before the first one.
+    Cancelled {
+        last_addr: Option<usize>,
+    },
 }
 
 impl From<WriteError> for ProgramError {
@@ -57,87 +203,1717 @@ impl From<WriteError> for ProgramError {
     }
 }
 
-pub struct Teensy {
-    sys: sys::SysTeensy,
+/// Options controlling how `Teensy::program_with` writes an image.
+#[derive(Clone, Debug, Default)]
+pub struct ProgramOptions {
+    /// Upper bound on the time the whole programming run may take.
+    ///
+    /// Each block already has its own timeout, but a degraded link can keep every block just
+    /// inside it and make a large flash crawl along indefinitely. Once this much time has passed
+    /// programming is aborted with `ProgramError::Stalled` before the next block is sent.
+    pub max_duration: Option<Duration>,
+    /// Keep writing the remaining blocks when one fails, rather than stopping at the first error.
+    ///
+    /// Meant for diagnosing marginal hardware. The failures are returned together as
+    /// `ProgramError::PartialFailure` once every block has been tried.
+    pub continue_on_error: bool,
+    /// Checked before each block is sent, programming stops with `ProgramError::Cancelled` once
+    /// it is set.
+    ///
+    /// Lets another thread, such as a UI, abort cleanly between blocks instead of killing the
+    /// programming thread in the middle of a transfer.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Timeout for the block at address 0, which HalfKay doesn't acknowledge until it has erased
+    /// the whole chip. Defaults to `erase_timeout` for the part's flash size.
+    pub erase_timeout: Option<Duration>,
+    /// Timeout for every other block, `DEFAULT_BLOCK_TIMEOUT` if not set.
+    pub block_timeout: Option<Duration>,
+    /// Pause between a block being accepted and the next one being sent, none by default.
+    ///
+    /// The block timeouts only bound how long the bootloader may take to accept a block. Some
+    /// boards have been seen to need a little more time after that before the next one, and fail
+    /// intermittently without it.
+    pub inter_block_delay: Duration,
+}
+
+/// How reports are handed to the bootloader on the wire.
+///
+/// HalfKay takes each block as a HID SET_REPORT control transfer, which is what the default
+/// describes. A different descriptor can be given through `ConnectOptions::protocol` for clone
+/// bootloaders or revisions that use other request values or report IDs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootloaderProtocol {
+    /// `bmRequestType` of the control transfer. `0x21` is a host to device class request
+    /// addressed to an interface.
+    pub request_type: u8,
+    /// `bRequest` of the control transfer. `9` is HID SET_REPORT.
+    pub request: u8,
+    /// `wValue` of the control transfer: the report type in the high byte (`2` for output) and
+    /// the report ID in the low byte.
+    pub value: u16,
+    /// `wIndex` of the control transfer, the interface the report is sent to.
+    pub index: u16,
+}
+
+impl BootloaderProtocol {
+    /// Stock HalfKay: an output report with ID 0, sent to interface 0.
+    pub const HALFKAY: Self = BootloaderProtocol {
+        request_type: 0x21,
+        request: 9,
+        value: 0x0200,
+        index: 0,
+    };
+
+    /// The report ID, which is the low byte of `value`.
+    pub fn report_id(&self) -> u8 {
+        self.value as u8
+    }
+}
+
+impl Default for BootloaderProtocol {
+    fn default() -> Self {
+        Self::HALFKAY
+    }
+}
+
+/// Where a backend's USB API takes the report ID from.
+///
+/// `Teensy` hands every backend just the report, the HalfKay header followed by the block data,
+/// and never a report ID. Each backend declares its placement as `REPORT_ID_PLACEMENT` and builds
+/// the buffer for its API with `report_bytes`, so the ID from `BootloaderProtocol::report_id` ends
+/// up in the same place on the wire whichever backend sent it. Adding or dropping a byte here
+/// shifts the whole block, and HalfKay will program it regardless.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ReportIdPlacement {
+    /// In the low byte of the control transfer's `wValue`, with the buffer holding only the
+    /// report. libusb and IOKit take it this way.
+    Setup,
+    /// As the first byte of the buffer, even when it is 0. The Windows HID API takes it this way.
+    #[cfg_attr(not(any(windows, test)), allow(dead_code))]
+    Prefix,
+}
+
+/// The buffer to hand a USB API that takes the report ID as described by `placement`.
+pub(crate) fn report_bytes(
+    buf: &[u8],
+    report_id: u8,
+    placement: ReportIdPlacement,
+) -> Cow<'_, [u8]> {
+    match placement {
+        ReportIdPlacement::Setup => Cow::Borrowed(buf),
+        ReportIdPlacement::Prefix => {
+            let mut bytes = Vec::with_capacity(buf.len() + 1);
+            bytes.push(report_id);
+            bytes.extend_from_slice(buf);
+            Cow::Owned(bytes)
+        }
+    }
+}
+
+/// Where a device is plugged in, as a USB bus number and the device's address on that bus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UsbPath {
+    pub bus: u8,
+    pub address: u8,
+}
+
+/// Options controlling how `Teensy::connect_with` talks to the bootloader.
+///
+/// The defaults match stock HalfKay. These are an escape hatch for clone or experimental
+/// bootloaders that frame blocks differently.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// Size of the address header sent before each block.
+    ///
+    /// Defaults to 64 bytes for parts with 512 or 1024 byte blocks and 2 bytes otherwise. A 2 byte
+    /// header holds a 16-bit address (or the address shifted right by 8 on parts with more than
+    /// 64K of flash), anything larger holds a 24-bit little endian address. Anything smaller is
+    /// rejected with `ConnectError::UnsupportedHeaderSize`.
+    pub header_size: Option<usize>,
+    /// Offset in each report at which the block data is written.
+    ///
+    /// Defaults to, and is never less than, the header size. Any bytes between the end of the
+    /// header and the data are zero.
+    pub data_offset: Option<usize>,
+    /// Pause between retries of a write the device didn't accept, `DEFAULT_RETRY_INTERVAL` if
+    /// not set.
+    ///
+    /// Shorter intervals help on fast hosts, longer ones reduce churn on a busy bus.
+    pub retry_interval: Option<Duration>,
+    /// How reports are sent, `BootloaderProtocol::HALFKAY` by default.
+    ///
+    /// The Windows backend goes through the HID driver, which always sends an output report, so
+    /// only the report ID is taken from this there.
+    pub protocol: BootloaderProtocol,
+    /// Only connect to the bootloader at this bus and address, rather than the first one found.
+    ///
+    /// Picks out one of several identical boards when they don't have usable serial numbers.
+    /// Only the libusb backend supports this, the others fail with
+    /// `ConnectError::PathUnsupported`.
+    pub path: Option<UsbPath>,
+    /// Have the Windows backend send every report with `HidD_SetOutputReport` rather than
+    /// overlapped `WriteFile`. Other backends ignore this.
+    ///
+    /// Some HID driver stacks never complete overlapped writes properly. The backend switches over
+    /// by itself after several overlapped writes fail in a row, this skips straight to it.
+    pub sync: bool,
+}
+
+/// What a successful programming run did.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramReport {
+    pub blocks_written: usize,
+    /// Blocks that were left out because they were all 0xFF, what the erase leaves behind.
+    pub blocks_skipped: usize,
+    /// Address of the first block written, `None` if nothing was.
+    pub first_addr: Option<usize>,
+    /// Address of the last block written, `None` if nothing was.
+    pub last_addr: Option<usize>,
+    /// Time from the first block being sent until the last one was accepted.
+    pub elapsed: Duration,
+    /// CRC-32 of the data of the blocks written, in the order they were sent, without their
+    /// headers. For comparing with the same image read back or checked offline.
+    pub crc32: u32,
+    /// The most times any one block had to be retried before the device took it. Block 0 isn't
+    /// counted, since the device may refuse writes while the chip erase it starts runs. Anything
+    /// but a handful points at a bad cable or hub, even though the run succeeded.
+    pub max_retries_on_any_block: usize,
+}
+
+/// How far a programming run has got, passed to the feedback callback before each block is sent.
+///
+/// Blank blocks are skipped without being sent, so on a sparse image the address jumps
+/// around. The block counts let a progress bar or ETA follow the work that is actually left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Address of the block about to be sent.
+    pub addr: usize,
+    /// Blocks of the image dealt with so far, sent or skipped.
+    pub blocks_processed: usize,
+    /// Blocks in the image.
+    pub blocks_total: usize,
+    /// Blocks sent so far.
+    pub blocks_transmitted: usize,
+    /// Blocks that will be sent by the end of the run.
+    pub blocks_to_transmit: usize,
+    /// Time since programming started.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Estimates how long the remaining blocks will take to send, `None` until one has been.
+    ///
+    /// Based on the average time per block sent so far, ignoring skipped blocks, so the estimate
+    /// doesn't lurch when the run crosses a blank region. The first block includes the chip
+    /// erase and makes early estimates pessimistic.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.blocks_transmitted == 0 {
+            return None;
+        }
+        let remaining = (self.blocks_to_transmit - self.blocks_transmitted) as u32;
+        Some(self.elapsed / self.blocks_transmitted as u32 * remaining)
+    }
+
+    /// Percentage of the blocks to be sent that have been, from 0 to 100.
+    ///
+    /// Counts only blocks that are sent, so it rises steadily across blank regions of a sparse
+    /// image. An image with nothing to send is complete from the start.
+    pub fn percent(&self) -> f32 {
+        if self.blocks_to_transmit == 0 {
+            return 100.0;
+        }
+        self.blocks_transmitted as f32 * 100.0 / self.blocks_to_transmit as f32
+    }
+}
+
+/// What `Teensy` needs from the USB stack to talk to HalfKay.
+///
+/// The platform backend implements this, and `Teensy::new_from_handle` accepts any other
+/// implementation, for embedders bringing their own USB stack or test harness.
+pub trait HalfKayTransport {
+    /// Sends one report, retrying every `retry_interval` until the device accepts it or
+    /// `timeout` has passed.
+    fn write(
+        &mut self,
+        buf: &[u8],
+        protocol: &BootloaderProtocol,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError>;
+
+    /// Whether the device is still attached. Transports that can't tell say it is.
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// How many times the last `write` was retried before the device took it. Transports that
+    /// don't count say 0.
+    fn last_write_retries(&self) -> usize {
+        0
+    }
+
+    /// Waits `duration` before the next block, for `ProgramOptions::inter_block_delay`.
+    fn pause(&mut self, duration: Duration) {
+        sleep(duration)
+    }
+
+    /// Length of the device's HID output report in bytes, not counting the report ID.
+    fn output_report_len(&mut self) -> Result<usize, sys::SystemError>;
+
+    /// Opens the same device again after it has dropped off the bus, failing if it isn't back.
+    ///
+    /// Only called after `is_connected` has said the device is gone, so transports that never
+    /// report that needn't implement it.
+    fn reconnect(&mut self) -> Result<(), ConnectError> {
+        Err(ConnectError::DeviceNotFound)
+    }
+}
+
+impl HalfKayTransport for sys::SysTeensy {
+    fn write(
+        &mut self,
+        buf: &[u8],
+        protocol: &BootloaderProtocol,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError> {
+        sys::SysTeensy::write(self, buf, protocol, timeout, retry_interval)
+    }
+
+    fn is_connected(&self) -> bool {
+        sys::SysTeensy::is_connected(self)
+    }
+
+    fn last_write_retries(&self) -> usize {
+        sys::SysTeensy::last_write_retries(self)
+    }
+
+    // The mock records pauses instead of sleeping through them
+    #[cfg(test)]
+    fn pause(&mut self, duration: Duration) {
+        sys::SysTeensy::pause(self, duration)
+    }
+
+    fn output_report_len(&mut self) -> Result<usize, sys::SystemError> {
+        sys::SysTeensy::output_report_len(self)
+    }
+
+    fn reconnect(&mut self) -> Result<(), ConnectError> {
+        sys::SysTeensy::reconnect(self)
+    }
+}
+
+/// A HalfKay bootloader and the open handle to it.
+///
+/// The handle is opened once, by `connect` or `new_from_handle`, and kept until this is dropped,
+/// so one `Teensy` can `detect_block_size`, `erase`, `program` and finally `boot` without
+/// reconnecting in between. Only the Teensy 2 parts, which drop off the bus while erasing, open
+/// it again, and then only during `program`. After `boot` the device has left the bootloader and
+/// every further write fails.
+pub struct Teensy<T: HalfKayTransport = sys::SysTeensy> {
+    sys: T,
     code_size: usize,
     block_size: usize,
     header_size: usize,
+    data_offset: usize,
+    retry_interval: Duration,
+    protocol: BootloaderProtocol,
 }
 
 impl Teensy {
     pub fn connect(mcu: Mcu) -> Result<Self, ConnectError> {
-        let header_size = if mcu.block_size == 512 || mcu.block_size == 1024 {
-            64
-        } else {
-            2
+        Self::connect_with(mcu, &ConnectOptions::default())
+    }
+
+    /// Connects to the bootloader plugged in at `bus` and `address`.
+    ///
+    /// The address is handed out when the device enumerates, so it changes whenever the board
+    /// resets, including on its way into the bootloader. Look it up once the bootloader is
+    /// running.
+    pub fn connect_by_path(mcu: Mcu, bus: u8, address: u8) -> Result<Self, ConnectError> {
+        let options = ConnectOptions {
+            path: Some(UsbPath { bus, address }),
+            ..Default::default()
         };
+        Self::connect_with(mcu, &options)
+    }
+
+    pub fn connect_with(mcu: Mcu, options: &ConnectOptions) -> Result<Self, ConnectError> {
+        if !BLOCK_SIZES.contains(&mcu.block_size) {
+            return Err(ConnectError::UnsupportedBlockSize(mcu.block_size));
+        }
+        let sys = sys::SysTeensy::connect(TEENSY_VENDOR_ID, TEENSY_PRODUCT_ID, options)?;
+        Self::new_from_handle(sys, mcu, options)
+    }
+
+    /// Asks a sketch running on the device `vid`:`pid` to reboot into the bootloader.
+    ///
+    /// This only works with firmware built on the Teensy core using one of the
+    /// `SEREMU_PRODUCT_IDS` USB types, whose serial emulation interface listens for a reboot
+    /// feature report. Sketches with a real serial port (CDC) don't have that interface and
+    /// sketches that don't use the Teensy core have no way to be rebooted at all, for those the
+    /// reset button is the only option.
+    ///
+    /// Returning `Ok` means the request was accepted. The bootloader takes a moment to appear
+    /// after that, so follow up with retried calls to `connect`.
+    pub fn reboot_application(vid: u16, pid: u16) -> Result<(), RebootError> {
+        sys::reboot_application(vid, pid)
+    }
+
+    /// Lists where every device currently running HalfKay is plugged in, any of which can be
+    /// picked with `ConnectOptions::path`.
+    ///
+    /// Like `ConnectOptions::path`, only the libusb backend supports this, the others fail with
+    /// `ConnectError::PathUnsupported`.
+    pub fn list() -> Result<Vec<UsbPath>, ConnectError> {
+        sys::list_devices(TEENSY_VENDOR_ID, TEENSY_PRODUCT_ID)
+    }
+}
+
+impl<T: HalfKayTransport> Teensy<T> {
+    /// Wraps an already open `transport`, configured as `connect_with` would be.
+    ///
+    /// `ConnectOptions::path` has no effect, finding the device is up to the caller.
+    pub fn new_from_handle(
+        transport: T,
+        mcu: Mcu,
+        options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        if !BLOCK_SIZES.contains(&mcu.block_size) {
+            return Err(ConnectError::UnsupportedBlockSize(mcu.block_size));
+        }
+        let header_size = options
+            .header_size
+            .unwrap_or_else(|| header_size(mcu.block_size));
+        if header_size < 2 {
+            return Err(ConnectError::UnsupportedHeaderSize(header_size));
+        }
+        let data_offset = options.data_offset.unwrap_or(header_size).max(header_size);
 
         Ok(Self {
-            sys: sys::SysTeensy::connect(TEENSY_VENDOR_ID, TEENSY_PRODUCT_ID)?,
+            sys: transport,
             code_size: mcu.code_size,
             block_size: mcu.block_size,
             header_size,
+            data_offset,
+            retry_interval: options.retry_interval.unwrap_or(DEFAULT_RETRY_INTERVAL),
+            protocol: options.protocol,
         })
     }
 
+    /// Works out the device's block size from the length of its HID output report.
+    ///
+    /// Each HalfKay report carries exactly one block plus its header, so this can be used to
+    /// check that the part the user asked for matches the one that is connected.
+    pub fn detect_block_size(&mut self) -> Result<usize, DetectError> {
+        let report_len = self.sys.output_report_len()?;
+        trace!("output report is {} bytes", report_len);
+        BLOCK_SIZES
+            .iter()
+            .copied()
+            .find(|&block_size| block_size + header_size(block_size) == report_len)
+            .ok_or(DetectError::UnknownReportLength(report_len))
+    }
+
+    /// Checks the device is still attached, without writing anything to it.
+    pub fn is_connected(&self) -> bool {
+        self.sys.is_connected()
+    }
+
     pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<(), WriteError> {
-        self.sys.write(buf, timeout)
+        trace!(
+            "writing {} bytes, timeout {:?}\n{}",
+            buf.len(),
+            timeout,
+            HexDump(buf)
+        );
+        self.sys
+            .write(buf, &self.protocol, timeout, self.retry_interval)
     }
 
     pub fn boot(&mut self) -> Result<(), WriteError> {
+        self.boot_with_timeout(DEFAULT_BLOCK_TIMEOUT)
+    }
+
+    /// Like `boot`, but giving the bootloader `timeout` to accept the command.
+    pub fn boot_with_timeout(&mut self, timeout: Duration) -> Result<(), WriteError> {
         let mut buf = Vec::<u8>::with_capacity(self.write_size());
         buf.extend(std::iter::repeat(0).take(self.write_size() as usize));
         buf[0] = 0xff;
         buf[1] = 0xff;
         buf[2] = 0xff;
-        self.write(&buf, Duration::from_millis(500))
+        trace!("sending boot command");
+        self.write(&buf, timeout)
+    }
+
+    pub fn program(
+        &mut self,
+        binary: &[u8],
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        self.program_with(binary, &ProgramOptions::default(), feedback)
     }
 
-    pub fn program(&mut self, binary: &[u8], feedback: impl Fn(usize)) -> Result<(), ProgramError> {
+    pub fn program_with(
+        &mut self,
+        binary: &[u8],
+        options: &ProgramOptions,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        self.program_from(binary, 0, options, None, feedback)
+    }
+
+    /// Programs a complete flash image and boots it, for callers that already have one in memory.
+    ///
+    /// `image` must be flat, starting at address 0 with unused flash filled with 0xFF, as
+    /// `load_file` and `parse_image` return it. It may be shorter than flash but has to be a whole
+    /// number of blocks of the part this was connected as.
+    pub fn flash_image(&mut self, image: &[u8]) -> Result<ProgramReport, ProgramError> {
+        if image.len() > self.code_size {
+            return Err(ProgramError::RegionTooLarge);
+        }
+        if image.len() % self.block_size != 0 {
+            return Err(ProgramError::BinaryRemainder);
+        }
+        let report = self.program(image, |_| {})?;
+        self.boot()?;
+        Ok(report)
+    }
+
+    /// Like `program`, but only reports how far along it is, as a percentage.
+    ///
+    /// `feedback` is called before each block is sent, with values that never go down, and with
+    /// 100 once the last block has been accepted.
+    pub fn program_with_percent(
+        &mut self,
+        binary: &[u8],
+        feedback: impl Fn(f32),
+    ) -> Result<ProgramReport, ProgramError> {
+        let report = self.program(binary, |progress| feedback(progress.percent()))?;
+        feedback(100.0);
+        Ok(report)
+    }
+
+    /// Programs `binary` into flash starting at `start_addr`, leaving everything before it alone.
+    ///
+    /// HalfKay only erases the chip when it is sent the block at address 0, so unless
+    /// `start_addr` is 0 nothing is erased: the region being written must already be blank or
+    /// the result will be garbage. `start_addr` must be a multiple of the block size.
+    pub fn program_region(
+        &mut self,
+        binary: &[u8],
+        start_addr: usize,
+        options: &ProgramOptions,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        if start_addr % self.block_size != 0 {
+            return Err(ProgramError::UnalignedStart(start_addr));
+        }
+        if start_addr + binary.len() > self.code_size {
+            return Err(ProgramError::RegionTooLarge);
+        }
+        self.program_from(binary, start_addr, options, None, feedback)
+    }
+
+    /// Programs only the blocks of `new` that differ from `old`, the image already on the device.
+    ///
+    /// HalfKay can't erase part of the flash: the whole chip is erased when block 0 is written and
+    /// at no other time, and a block that isn't blank can't be rewritten. So this only saves time
+    /// when every changed block is blank in `old`, in which case block 0 is left alone and just
+    /// those blocks are sent. Otherwise the chip has to be erased, and all of `new` is programmed
+    /// as `program_with` would.
+    pub fn program_diff(
+        &mut self,
+        new: &[u8],
+        old: &[u8],
+        options: &ProgramOptions,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        if new.len() != old.len() {
+            return Err(ProgramError::DiffLengthMismatch);
+        }
+
+        let needs_erase = new
+            .chunks(self.block_size)
+            .zip(old.chunks(self.block_size))
+            .enumerate()
+            .any(|(i, (new, old))| new != old && (i == 0 || old.iter().any(|&x| x != 0xFF)));
+        if needs_erase {
+            debug!("changed blocks aren't blank on the device, programming the whole image");
+            self.program_from(new, 0, options, None, feedback)
+        } else {
+            self.program_from(new, 0, options, Some(old), feedback)
+        }
+    }
+
+    fn program_from(
+        &mut self,
+        binary: &[u8],
+        start_addr: usize,
+        options: &ProgramOptions,
+        old: Option<&[u8]>,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        if binary.is_empty() {
+            return Err(ProgramError::EmptyImage);
+        }
+
         let binary_chunks = binary.chunks_exact(self.block_size);
         if !binary_chunks.remainder().is_empty() {
             return Err(ProgramError::BinaryRemainder);
         }
 
-        let mut buf = Vec::with_capacity(self.write_size());
-        for (addr, chunk) in (0..self.code_size)
+        let block_size = self.block_size;
+        let skip = |addr: usize, chunk: &[u8]| match old {
+            // Already on the device, see `program_diff`
+            Some(old) => chunk == &old[addr - start_addr..][..block_size],
+            None => is_erased(addr, chunk),
+        };
+        let blocks = (start_addr..self.code_size)
             .step_by(self.block_size)
-            .zip(binary_chunks)
-        {
-            if addr != 0 && chunk.iter().all(|&x| x == 0xFF) {
+            .zip(binary_chunks);
+        let blocks_to_transmit = blocks
+            .clone()
+            .filter(|&(addr, chunk)| !skip(addr, chunk))
+            .count();
+
+        self.send_blocks(
+            blocks.map(|(addr, chunk)| Ok((addr, Cow::Borrowed(chunk)))),
+            skip,
+            binary.len() / self.block_size,
+            Some(blocks_to_transmit),
+            options,
+            feedback,
+        )
+    }
+
+    /// Programs `total_len` bytes read from `reader` into flash from address 0, a block at a
+    /// time.
+    ///
+    /// Behaves like `program_with`, but only one block is held in memory, so large images can be
+    /// streamed from a file or a decompressor. If `total_len` isn't a whole number of blocks the
+    /// last one is padded with 0xFF. Since blank blocks can't be counted before they are read,
+    /// `Progress::blocks_to_transmit` starts out as every block and drops as they are skipped.
+    pub fn program_reader(
+        &mut self,
+        reader: impl Read,
+        total_len: usize,
+        options: &ProgramOptions,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        if total_len == 0 {
+            return Err(ProgramError::EmptyImage);
+        }
+        if total_len > self.code_size {
+            return Err(ProgramError::RegionTooLarge);
+        }
+
+        let block_size = self.block_size;
+        let mut reader = reader.take(total_len as u64);
+        let blocks = (0..total_len).step_by(block_size).map(move |addr| {
+            let mut chunk = vec![DEFAULT_FILL_BYTE; block_size];
+            let len = block_size.min(total_len - addr);
+            reader
+                .read_exact(&mut chunk[..len])
+                .map_err(|err| ProgramError::ReadError(err.kind()))?;
+            Ok((addr, Cow::Owned(chunk)))
+        });
+
+        self.send_blocks(
+            blocks,
+            is_erased,
+            total_len.div_ceil(block_size),
+            None,
+            options,
+            feedback,
+        )
+    }
+
+    /// Sends each of `blocks`, an address and a whole block of data, except those `skip` picks
+    /// out. `blocks_to_transmit` is `None` if it isn't known up front.
+    fn send_blocks<'a>(
+        &mut self,
+        blocks: impl Iterator<Item = Result<(usize, Cow<'a, [u8]>), ProgramError>>,
+        skip: impl Fn(usize, &[u8]) -> bool,
+        blocks_total: usize,
+        blocks_to_transmit: Option<usize>,
+        options: &ProgramOptions,
+        feedback: impl Fn(&Progress),
+    ) -> Result<ProgramReport, ProgramError> {
+        let start = Instant::now();
+        let mut last_block = start;
+        let mut report = ProgramReport::default();
+        let mut failures = Vec::new();
+        let mut crc = Crc32::new();
+        // Whether the last block sent was accepted, and `inter_block_delay` applies
+        let mut pause = false;
+        // Every block goes out as its own report. HalfKay's output report is exactly one header
+        // plus one block long, so consecutive blocks can't be coalesced into a larger transfer;
+        // the best we can do is build each one in the same buffer.
+        let mut buf = Vec::with_capacity(self.write_size());
+        // How long each block took to be accepted, only collected when it will be logged
+        let mut timings = if log_enabled!(Level::Trace) {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        for (i, block) in blocks.enumerate() {
+            let (addr, chunk) = block?;
+            if skip(addr, &chunk) {
+                report.blocks_skipped += 1;
                 continue;
             }
 
-            feedback(addr);
+            if let Some(cancel) = &options.cancel {
+                if cancel.load(Ordering::SeqCst) {
+                    debug!("cancelled before block at {:#08x}", addr);
+                    return Err(ProgramError::Cancelled {
+                        last_addr: report.last_addr,
+                    });
+                }
+            }
 
-            if self.block_size <= 256 {
-                buf.resize(2, 0);
-                if self.code_size < 0x10000 {
-                    buf[0] = addr as u8;
-                    buf[1] = (addr >> 8) as u8;
-                } else {
-                    buf[0] = (addr >> 8) as u8;
-                    buf[1] = (addr >> 16) as u8;
+            // Only give up once something has been written, or there'd be nothing to report
+            if let (Some(max_duration), Some(last_addr)) = (options.max_duration, report.last_addr)
+            {
+                if start.elapsed() > max_duration {
+                    debug!(
+                        "giving up after {:?}, last block written {:?} ago",
+                        start.elapsed(),
+                        last_block.elapsed()
+                    );
+                    return Err(ProgramError::Stalled { last_addr });
                 }
-                buf.extend_from_slice(chunk);
+            }
+
+            feedback(&Progress {
+                addr,
+                blocks_processed: i,
+                blocks_total,
+                blocks_transmitted: report.blocks_written + failures.len(),
+                blocks_to_transmit: blocks_to_transmit
+                    .unwrap_or(blocks_total - report.blocks_skipped),
+                elapsed: start.elapsed(),
+            });
+
+            if pause && options.inter_block_delay > Duration::from_millis(0) {
+                trace!(
+                    "waiting {:?} before the next block",
+                    options.inter_block_delay
+                );
+                self.sys.pause(options.inter_block_delay);
+            }
+
+            // HalfKay takes one block at a time and only accepts the next write once it has
+            // programmed the last, so there is nothing to pipeline here. Filling the buffer is a
+            // copy of one block, next to nothing beside the write.
+            self.fill_block(&mut buf, addr, &chunk);
+            trace!("programming block at {:#08x}", addr);
+            let timeout = if addr == 0 {
+                options
+                    .erase_timeout
+                    .unwrap_or_else(|| erase_timeout(self.code_size))
             } else {
-                buf.resize(64, 0);
+                options.block_timeout.unwrap_or(DEFAULT_BLOCK_TIMEOUT)
+            };
+            let sent = Instant::now();
+            let res = self.write(&buf, timeout);
+            if let Some(timings) = &mut timings {
+                let took = sent.elapsed();
+                trace!("block at {:#08x} took {:?}", addr, took);
+                timings.push(took);
+            }
+            match res {
+                Ok(()) => {
+                    pause = true;
+                    last_block = Instant::now();
+                    report.blocks_written += 1;
+                    crc.update(&chunk);
+                    let retries = self.sys.last_write_retries();
+                    if retries > 0 {
+                        debug!("block at {:#08x} took {} retries", addr, retries);
+                    }
+                    if addr != 0 {
+                        report.max_retries_on_any_block =
+                            report.max_retries_on_any_block.max(retries);
+                    }
+                    report.first_addr = report.first_addr.or(Some(addr));
+                    report.last_addr = Some(addr);
+                    if addr == 0 && self.block_size <= AVR_MAX_BLOCK_SIZE {
+                        self.reconnect_if_gone()?;
+                    }
+                }
+                Err(WriteError::Disconnected) => {
+                    debug!("device went away writing the block at {:#08x}", addr);
+                    return Err(ProgramError::Disconnected {
+                        last_addr: report.last_addr,
+                    });
+                }
+                Err(err) if options.continue_on_error => {
+                    pause = false;
+                    debug!("block at {:#08x} failed: {:?}", addr, err);
+                    failures.push((addr, err));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if let Some(mut timings) = timings {
+            log_block_timings(&mut timings);
+        }
+
+        if !failures.is_empty() {
+            return Err(ProgramError::PartialFailure(failures));
+        }
+
+        report.elapsed = start.elapsed();
+        report.crc32 = crc.finish();
+        Ok(report)
+    }
+
+    /// The Teensy 2 bootloaders can drop off the bus for a moment while erasing, after accepting
+    /// the first block. Waits for the device to come back and opens it again if so.
+    fn reconnect_if_gone(&mut self) -> Result<(), ProgramError> {
+        if self.sys.is_connected() {
+            return Ok(());
+        }
+
+        debug!("device went away after the first block, reconnecting");
+        let begin = Instant::now();
+        loop {
+            match self.sys.reconnect() {
+                Ok(()) => return Ok(()),
+                Err(ConnectError::DeviceNotFound) if begin.elapsed() < AVR_RECONNECT_TIMEOUT => {
+                    sleep(self.retry_interval)
+                }
+                Err(err) => return Err(ProgramError::ReconnectFailed(err)),
+            }
+        }
+    }
+
+    /// Erases the whole flash without programming anything.
+    ///
+    /// HalfKay erases the chip when it receives the block for address 0, so this writes a blank
+    /// first block and allows it the same long timeout programming does by default,
+    /// `erase_timeout` for the part's flash size.
+    pub fn erase(&mut self) -> Result<(), WriteError> {
+        self.erase_with_timeout(erase_timeout(self.code_size))
+    }
+
+    /// Like `erase`, but giving the bootloader `timeout` to finish erasing.
+    pub fn erase_with_timeout(&mut self, timeout: Duration) -> Result<(), WriteError> {
+        let blank = vec![0xFF; self.block_size];
+        let mut buf = Vec::with_capacity(self.write_size());
+        self.fill_block(&mut buf, 0, &blank);
+        trace!("erasing");
+        self.write(&buf, timeout)
+    }
+
+    /// Reads the whole flash back, block by block, into `out`.
+    ///
+    /// None of the HalfKay versions on the supported parts implement a read command, the
+    /// bootloader only accepts writes, so for now this always fails with `DumpError::Unsupported`.
+    pub fn dump(&mut self, _out: &mut impl Write) -> Result<(), DumpError> {
+        Err(DumpError::Unsupported)
+    }
+
+    /// Fills `buf` with the header for `addr` followed by `chunk`.
+    ///
+    /// A 2 byte header holds the address as is when every block address fits in 16 bits, that is
+    /// when `code_size` is at most 0x10000. Larger parts send the address shifted right by 8,
+    /// which only works because their blocks are 256 byte aligned.
+    fn fill_block(&self, buf: &mut Vec<u8>, addr: usize, chunk: &[u8]) {
+        buf.clear();
+        buf.resize(self.data_offset, 0);
+        if self.header_size == 2 {
+            if self.code_size <= 0x10000 {
                 buf[0] = addr as u8;
                 buf[1] = (addr >> 8) as u8;
-                buf[2] = (addr >> 16) as u8;
-                buf.extend_from_slice(chunk);
+            } else {
+                buf[0] = (addr >> 8) as u8;
+                buf[1] = (addr >> 16) as u8;
             }
-
-            self.write(
-                &buf,
-                Duration::from_millis(if addr == 0 { 5000 } else { 500 }),
-            )?;
+        } else {
+            buf[0] = addr as u8;
+            buf[1] = (addr >> 8) as u8;
+            buf[2] = (addr >> 16) as u8;
         }
+        buf.extend_from_slice(chunk);
+    }
+
+    fn write_size(&self) -> usize {
+        self.block_size + self.data_offset
+    }
+}
+
+/// Whether the block at `addr` can be left out, because the erase already left it as it should be.
+///
+/// Only 0xFF is what erased flash reads as, so a block of any other byte is real data. Block 0
+/// triggers the erase, so it is always sent.
+fn is_erased(addr: usize, chunk: &[u8]) -> bool {
+    addr != 0 && chunk.iter().all(|&x| x == DEFAULT_FILL_BYTE)
+}
+
+/// Logs the spread of per-block write times, to tell a slow bus from a slow bootloader.
+fn log_block_timings(timings: &mut [Duration]) {
+    if timings.is_empty() {
+        return;
+    }
+    timings.sort();
+    let total: Duration = timings.iter().sum();
+    trace!(
+        "{} blocks in {:?}: min {:?}, max {:?}, mean {:?}, p95 {:?}",
+        timings.len(),
+        total,
+        timings[0],
+        timings[timings.len() - 1],
+        total / timings.len() as u32,
+        percentile(timings, 95)
+    );
+}
+
+/// The nearest-rank `pct`th percentile of `sorted`, which must not be empty.
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    let rank = (sorted.len() * pct).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// How long to wait for the block at address 0 to be accepted on a part with `code_size` bytes of
+/// flash, since HalfKay erases the whole chip before acknowledging it.
+///
+/// The erase takes longer the more flash there is, so this allows 2 seconds plus 12 seconds per
+/// megabyte, clamped to between 5 seconds and a minute. The floor is the fixed 5 seconds this used
+/// to be for every part, so small parts don't get less time than they always had.
+pub fn erase_timeout(code_size: usize) -> Duration {
+    let ms = 2000 + 12000 * code_size as u64 / 0x10_0000;
+    Duration::from_millis(ms.clamp(5000, 60_000))
+}
+
+/// Size of the address header that precedes each block.
+fn header_size(block_size: usize) -> usize {
+    if block_size == 512 || block_size == 1024 {
+        64
+    } else {
+        2
+    }
+}
 
+/// Formats a buffer as lines of 16 space separated hex bytes prefixed by their offset.
+struct HexDump<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, line) in self.0.chunks(16).enumerate() {
+            if n != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:04x}:", n * 16)?;
+            for b in line {
+                write!(f, " {:02x}", b)?;
+            }
+        }
         Ok(())
     }
+}
 
-    fn write_size(&self) -> usize {
-        self.block_size + self.header_size
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn program_empty_image() {
+        let mcu = crate::parse_mcu("TEENSYLC").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        assert_eq!(teensy.program(&[], |_| {}), Err(ProgramError::EmptyImage));
+        assert!(teensy.sys.writes.is_empty());
+    }
+
+    #[test]
+    fn program_stalled() {
+        let mcu = crate::parse_mcu("TEENSYLC").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let options = ProgramOptions {
+            max_duration: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        assert_eq!(
+            teensy.program_with(&[0; 1024], &options, |_| {}),
+            Err(ProgramError::Stalled { last_addr: 0 })
+        );
+        assert_eq!(teensy.sys.writes.len(), 1);
+    }
+
+    /// An image of `blocks` blocks where only the blocks in `used` hold data.
+    fn sparse_image(block_size: usize, blocks: usize, used: &[usize]) -> Vec<u8> {
+        let mut image = vec![0xFF; block_size * blocks];
+        for &n in used {
+            for b in &mut image[n * block_size..(n + 1) * block_size] {
+                *b = n as u8;
+            }
+        }
+        image
+    }
+
+    /// Programs `image` and returns the header of each block written.
+    fn program_headers(mcu: &str, image: &[u8]) -> Vec<Vec<u8>> {
+        let mcu = crate::parse_mcu(mcu).unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.program(image, |_| {}).unwrap();
+
+        let data_offset = teensy.data_offset;
+        teensy
+            .sys
+            .writes
+            .iter()
+            .map(|(buf, _)| {
+                assert_eq!(buf.len(), data_offset + mcu.block_size);
+                buf[..data_offset].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn program_large_block_header() {
+        // mk20dx256: 1024 byte blocks, 64 byte header holding a 24-bit address
+        let image = sparse_image(1024, 4, &[0, 2, 3]);
+        let headers = program_headers("TEENSY31", &image);
+
+        let expected: Vec<Vec<u8>> = [0x0, 0x800, 0xC00]
+            .iter()
+            .map(|&addr: &usize| {
+                let mut header = vec![0; 64];
+                header[..3].copy_from_slice(&addr.to_le_bytes()[..3]);
+                header
+            })
+            .collect();
+        assert_eq!(headers, expected);
+    }
+
+    #[test]
+    fn program_small_block_16bit_header() {
+        // atmega32u4: 128 byte blocks, less than 64K of flash so the address is sent as is
+        let image = sparse_image(128, 4, &[0, 1, 3]);
+        let headers = program_headers("atmega32u4", &image);
+        assert_eq!(
+            headers,
+            vec![vec![0x00, 0x00], vec![0x80, 0x00], vec![0x80, 0x01]]
+        );
+    }
+
+    #[test]
+    fn program_small_block_24bit_header() {
+        // at90usb1286: 256 byte blocks, more than 64K of flash so the address is sent shifted by 8
+        let image = sparse_image(256, 0x102, &[0, 0x101]);
+        let headers = program_headers("at90usb1286", &image);
+        assert_eq!(headers, vec![vec![0x00, 0x00], vec![0x01, 0x01]]);
+    }
+
+    #[test]
+    fn report_id_placement() {
+        let report = [0x00, 0x08, 0x00, 0xAA, 0x55];
+        assert_eq!(
+            &*report_bytes(&report, 3, ReportIdPlacement::Setup),
+            &[0x00, 0x08, 0x00, 0xAA, 0x55]
+        );
+        assert_eq!(
+            &*report_bytes(&report, 3, ReportIdPlacement::Prefix),
+            &[0x03, 0x00, 0x08, 0x00, 0xAA, 0x55]
+        );
+        // Report ID 0 still takes up the first byte
+        assert_eq!(
+            &*report_bytes(&report, 0, ReportIdPlacement::Prefix),
+            &[0x00, 0x00, 0x08, 0x00, 0xAA, 0x55]
+        );
+    }
+
+    #[test]
+    fn program_report() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let image = sparse_image(1024, 5, &[0, 2, 3]);
+        let report = teensy.program(&image, |_| {}).unwrap();
+        let sent: Vec<u8> = [&image[..0x400], &image[0x800..0x1000]].concat();
+        assert_eq!(
+            report,
+            ProgramReport {
+                blocks_written: 3,
+                blocks_skipped: 2,
+                first_addr: Some(0),
+                last_addr: Some(0xC00),
+                elapsed: report.elapsed,
+                crc32: crate::crc32(&sent),
+                max_retries_on_any_block: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn program_counts_retries() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        // The erase block doesn't count
+        teensy.sys.retries = vec![(0, 20), (1, 2), (2, 4)];
+        let report = teensy
+            .program(&sparse_image(1024, 4, &[0, 2, 3]), |_| {})
+            .unwrap();
+        assert_eq!(report.max_retries_on_any_block, 4);
+    }
+
+    #[test]
+    fn program_progress() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let seen = RefCell::new(Vec::new());
+        teensy
+            .program(&sparse_image(1024, 5, &[0, 2, 3]), |progress| {
+                assert_eq!(progress.blocks_total, 5);
+                assert_eq!(progress.blocks_to_transmit, 3);
+                seen.borrow_mut().push((
+                    progress.addr,
+                    progress.blocks_processed,
+                    progress.blocks_transmitted,
+                ));
+            })
+            .unwrap();
+        assert_eq!(
+            seen.into_inner(),
+            vec![(0, 0, 0), (0x800, 2, 1), (0xC00, 3, 2)]
+        );
+
+        let progress = Progress {
+            addr: 0x8000,
+            blocks_processed: 32,
+            blocks_total: 256,
+            blocks_transmitted: 2,
+            blocks_to_transmit: 6,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(progress.eta(), Some(Duration::from_secs(4)));
+        let first = Progress {
+            blocks_transmitted: 0,
+            ..progress
+        };
+        assert_eq!(first.eta(), None);
+    }
+
+    #[test]
+    fn flash_image_checks_size() {
+        let mcu = crate::parse_mcu("TEENSYLC").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        assert_eq!(
+            teensy.flash_image(&vec![0; mcu.code_size + mcu.block_size]),
+            Err(ProgramError::RegionTooLarge)
+        );
+        assert_eq!(
+            teensy.flash_image(&[0; 100]),
+            Err(ProgramError::BinaryRemainder)
+        );
+        assert!(teensy.sys.writes.is_empty());
+
+        let report = teensy.flash_image(&sparse_image(512, 3, &[0, 2])).unwrap();
+        assert_eq!(report.blocks_written, 2);
+        // The two blocks and the boot command
+        assert_eq!(teensy.sys.writes.len(), 3);
+    }
+
+    #[test]
+    fn program_percent() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let seen = RefCell::new(Vec::new());
+        teensy
+            .program_with_percent(&sparse_image(1024, 6, &[0, 2, 3, 5]), |percent| {
+                seen.borrow_mut().push(percent)
+            })
+            .unwrap();
+        assert_eq!(seen.into_inner(), vec![0.0, 25.0, 50.0, 75.0, 100.0]);
+    }
+
+    #[test]
+    fn program_avr_ihex() {
+        let mcu = crate::parse_mcu("TEENSY2").unwrap();
+        let (binary, _, _) =
+            crate::load_file("tests/blink_avr.ihex", crate::FileHint::IHEX, &mcu).unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.program(&binary, |_| {}).unwrap();
+
+        // Blocks 0 and 2, with plain 16-bit addresses
+        let headers: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| &buf[..2]).collect();
+        assert_eq!(headers, vec![&[0x00, 0x00], &[0x00, 0x01]]);
+        let (buf, _) = &teensy.sys.writes[1];
+        assert_eq!(buf.len(), 2 + 128);
+        assert_eq!(&buf[2..6], &[0x56, 0x9A, 0x4E, 0x9A]);
+    }
+
+    #[test]
+    fn program_small_block_address_boundary() {
+        // Exactly 64K: every block address still fits in 16 bits
+        let mcu = Mcu {
+            code_size: 0x10000,
+            block_size: 256,
+        };
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy
+            .program(&sparse_image(256, 0x100, &[0, 0xFE]), |_| {})
+            .unwrap();
+        let headers: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| &buf[..2]).collect();
+        assert_eq!(headers, vec![&[0x00, 0x00], &[0x00, 0xFE]]);
+
+        // Just over 64K: the block at 0x10000 needs the shifted encoding
+        let mcu = Mcu {
+            code_size: 0x10100,
+            block_size: 256,
+        };
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy
+            .program(&sparse_image(256, 0x101, &[0, 0x100]), |_| {})
+            .unwrap();
+        let headers: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| &buf[..2]).collect();
+        assert_eq!(headers, vec![&[0x00, 0x00], &[0x00, 0x01]]);
+    }
+
+    #[test]
+    fn program_region_skips_earlier_blocks() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy
+            .program_region(
+                &sparse_image(1024, 2, &[0, 1]),
+                0x800,
+                &ProgramOptions::default(),
+                |_| {},
+            )
+            .unwrap();
+
+        let addrs: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| buf[1]).collect();
+        assert_eq!(addrs, vec![0x08, 0x0C]);
+        assert!(teensy
+            .sys
+            .writes
+            .iter()
+            .all(|(_, timeout)| *timeout == Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn program_region_checks_bounds() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        assert_eq!(
+            teensy.program_region(&[0; 1024], 0x100, &ProgramOptions::default(), |_| {}),
+            Err(ProgramError::UnalignedStart(0x100))
+        );
+        assert_eq!(
+            teensy.program_region(
+                &[0; 2048],
+                mcu.code_size - 1024,
+                &ProgramOptions::default(),
+                |_| {}
+            ),
+            Err(ProgramError::RegionTooLarge)
+        );
+        assert!(teensy.sys.writes.is_empty());
+    }
+
+    #[test]
+    fn connect_rejects_unsupported_block_size() {
+        let mcu = Mcu {
+            code_size: 0x10000,
+            block_size: 64,
+        };
+        match Teensy::connect(mcu) {
+            Err(ConnectError::UnsupportedBlockSize(64)) => {}
+            Err(err) => panic!("expected UnsupportedBlockSize, got {:?}", err),
+            Ok(_) => panic!("expected UnsupportedBlockSize, connected"),
+        }
+    }
+
+    #[test]
+    fn new_from_handle_custom_transport() {
+        /// Accepts everything and only counts the reports.
+        struct Counter(usize);
+
+        impl HalfKayTransport for Counter {
+            fn write(
+                &mut self,
+                _buf: &[u8],
+                _protocol: &BootloaderProtocol,
+                _timeout: Duration,
+                _retry_interval: Duration,
+            ) -> Result<(), WriteError> {
+                self.0 += 1;
+                Ok(())
+            }
+
+            fn output_report_len(&mut self) -> Result<usize, sys::SystemError> {
+                Ok(1024 + 64)
+            }
+        }
+
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy =
+            Teensy::new_from_handle(Counter(0), mcu, &ConnectOptions::default()).unwrap();
+        assert!(teensy.is_connected());
+        assert_eq!(teensy.detect_block_size(), Ok(1024));
+        teensy
+            .program(&sparse_image(1024, 4, &[0, 2]), |_| {})
+            .unwrap();
+        teensy.boot().unwrap();
+        assert_eq!(teensy.sys.0, 3);
+    }
+
+    #[test]
+    fn connect_rejects_short_header() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        for size in 0..2 {
+            let options = ConnectOptions {
+                header_size: Some(size),
+                ..Default::default()
+            };
+            match Teensy::connect_with(mcu, &options) {
+                Err(ConnectError::UnsupportedHeaderSize(s)) if s == size => {}
+                Err(err) => panic!("expected UnsupportedHeaderSize, got {:?}", err),
+                Ok(_) => panic!("expected UnsupportedHeaderSize, connected"),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_with_header_override() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let options = ConnectOptions {
+            header_size: Some(4),
+            data_offset: Some(8),
+            ..Default::default()
+        };
+        let mut teensy = Teensy::connect_with(mcu, &options).unwrap();
+        teensy
+            .program(&sparse_image(1024, 2, &[0, 1]), |_| {})
+            .unwrap();
+
+        let (buf, _) = &teensy.sys.writes[1];
+        assert_eq!(buf.len(), 8 + 1024);
+        assert_eq!(&buf[..8], &[0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert!(buf[8..].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn is_connected_follows_device() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        assert!(teensy.is_connected());
+        teensy.sys.connected = false;
+        assert!(!teensy.is_connected());
+    }
+
+    #[test]
+    fn dump_unsupported() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(teensy.dump(&mut out), Err(DumpError::Unsupported));
+        assert!(out.is_empty());
+    }
+
+    /// Times the host side of programming a full small block part, where the per block overhead
+    /// matters most. Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_program_small_blocks() {
+        let mcu = crate::parse_mcu("TEENSY2PP").unwrap();
+        let image = vec![0; mcu.code_size];
+        let runs = 100;
+
+        let start = Instant::now();
+        for _ in 0..runs {
+            let mut teensy = Teensy::connect(mcu).unwrap();
+            teensy.program(&image, |_| {}).unwrap();
+            assert_eq!(teensy.sys.writes.len(), mcu.code_size / mcu.block_size);
+        }
+        println!(
+            "{} blocks of {} bytes: {:?} per image",
+            mcu.code_size / mcu.block_size,
+            mcu.block_size,
+            start.elapsed() / runs
+        );
+    }
+
+    #[test]
+    fn detect_block_size() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+
+        teensy.sys.report_len = 1024 + 64;
+        assert_eq!(teensy.detect_block_size(), Ok(1024));
+        teensy.sys.report_len = 128 + 2;
+        assert_eq!(teensy.detect_block_size(), Ok(128));
+        teensy.sys.report_len = 64;
+        assert_eq!(
+            teensy.detect_block_size(),
+            Err(DetectError::UnknownReportLength(64))
+        );
+    }
+
+    #[test]
+    fn program_cancelled() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let options = ProgramOptions {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        let res = teensy.program_with(&sparse_image(1024, 4, &[0, 1, 2, 3]), &options, |p| {
+            if p.addr == 0x800 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+        });
+        assert_eq!(
+            res,
+            Err(ProgramError::Cancelled {
+                last_addr: Some(0x800)
+            })
+        );
+        assert_eq!(teensy.sys.writes.len(), 3);
+    }
+
+    #[test]
+    fn erase_timeout_scales_with_flash() {
+        assert_eq!(erase_timeout(0x8000), Duration::from_millis(5000));
+        assert_eq!(erase_timeout(0x40000), Duration::from_millis(5000));
+        assert_eq!(erase_timeout(0x80000), Duration::from_millis(8000));
+        assert_eq!(erase_timeout(0x10_0000), Duration::from_millis(14000));
+        assert_eq!(erase_timeout(0x80_0000), Duration::from_millis(60000));
+
+        let mcu = crate::parse_mcu("TEENSY2").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let options = ProgramOptions {
+            erase_timeout: Some(Duration::from_millis(1234)),
+            ..Default::default()
+        };
+        teensy
+            .program_with(&sparse_image(128, 2, &[0, 1]), &options, |_| {})
+            .unwrap();
+        let timeouts: Vec<_> = teensy.sys.writes.iter().map(|&(_, t)| t).collect();
+        assert_eq!(
+            timeouts,
+            vec![Duration::from_millis(1234), Duration::from_millis(500)]
+        );
+    }
+
+    #[test]
+    fn block_and_boot_timeouts() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let options = ProgramOptions {
+            erase_timeout: Some(Duration::from_millis(8000)),
+            block_timeout: Some(Duration::from_millis(250)),
+            ..Default::default()
+        };
+        teensy
+            .program_with(&sparse_image(1024, 3, &[0, 1, 2]), &options, |_| {})
+            .unwrap();
+        teensy
+            .boot_with_timeout(Duration::from_millis(1000))
+            .unwrap();
+        let timeouts: Vec<_> = teensy
+            .sys
+            .writes
+            .iter()
+            .map(|&(_, t)| t.as_millis())
+            .collect();
+        assert_eq!(timeouts, vec![8000, 250, 250, 1000]);
+    }
+
+    #[test]
+    fn erase_timeout_override() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.erase().unwrap();
+        teensy
+            .erase_with_timeout(Duration::from_millis(8000))
+            .unwrap();
+        let timeouts: Vec<_> = teensy.sys.writes.iter().map(|&(_, t)| t).collect();
+        assert_eq!(
+            timeouts,
+            vec![erase_timeout(mcu.code_size), Duration::from_millis(8000)]
+        );
+    }
+
+    #[test]
+    fn block_timing_percentile() {
+        let ms: Vec<_> = (1..=20).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&ms, 95), Duration::from_millis(19));
+        assert_eq!(percentile(&ms, 100), Duration::from_millis(20));
+        assert_eq!(percentile(&ms[..1], 95), Duration::from_millis(1));
+        assert_eq!(percentile(&ms, 0), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn program_diff_blank_blocks() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let old = sparse_image(1024, 4, &[0, 1]);
+        let new = sparse_image(1024, 4, &[0, 1, 3]);
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let report = teensy
+            .program_diff(&new, &old, &ProgramOptions::default(), |_| {})
+            .unwrap();
+
+        // Block 0 is unchanged and must not be sent, or the chip would be erased
+        let addrs: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| buf[1]).collect();
+        assert_eq!(addrs, vec![0x0C]);
+        assert_eq!(report.blocks_written, 1);
+        assert_eq!(report.blocks_skipped, 3);
+    }
+
+    #[test]
+    fn program_diff_needs_erase() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let old = sparse_image(1024, 4, &[0, 1]);
+        let mut new = old.clone();
+        new[0x400] = 0;
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy
+            .program_diff(&new, &old, &ProgramOptions::default(), |_| {})
+            .unwrap();
+
+        // Block 1 can't be rewritten in place, so everything goes out after an erase
+        let addrs: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| buf[1]).collect();
+        assert_eq!(addrs, vec![0x00, 0x04]);
+
+        assert_eq!(
+            teensy.program_diff(&new, &old[..1024], &ProgramOptions::default(), |_| {}),
+            Err(ProgramError::DiffLengthMismatch)
+        );
+    }
+
+    #[test]
+    fn program_continue_on_error() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let image = sparse_image(1024, 4, &[0, 1, 2, 3]);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.failing = vec![1];
+        assert_eq!(
+            teensy.program(&image, |_| {}),
+            Err(ProgramError::WriteError(WriteError::Timeout))
+        );
+        assert_eq!(teensy.sys.writes.len(), 2);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.failing = vec![1, 3];
+        let options = ProgramOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            teensy.program_with(&image, &options, |_| {}),
+            Err(ProgramError::PartialFailure(vec![
+                (0x400, WriteError::Timeout),
+                (0xC00, WriteError::Timeout),
+            ]))
+        );
+        assert_eq!(teensy.sys.writes.len(), 4);
+    }
+
+    #[test]
+    fn program_reader_streams_blocks() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut image = sparse_image(1024, 4, &[0, 2]);
+        image.extend(vec![7; 100]);
+
+        let progress = RefCell::new(Vec::new());
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let report = teensy
+            .program_reader(&image[..], image.len(), &ProgramOptions::default(), |p| {
+                progress.borrow_mut().push(p.clone())
+            })
+            .unwrap();
+        assert_eq!(report.blocks_written, 3);
+        assert_eq!(report.blocks_skipped, 2);
+        let last = progress.borrow().last().cloned().unwrap();
+        assert_eq!((last.addr, last.blocks_to_transmit), (0x1000, 3));
+
+        // The short last block goes out padded, exactly as if the whole image had been given
+        image.resize(5 * 1024, 0xFF);
+        let mut expected = Teensy::connect(mcu).unwrap();
+        expected.program(&image, |_| {}).unwrap();
+        assert_eq!(teensy.sys.writes, expected.sys.writes);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        assert_eq!(
+            teensy.program_reader(&image[..1500], 2048, &ProgramOptions::default(), |_| {}),
+            Err(ProgramError::ReadError(io::ErrorKind::UnexpectedEof))
+        );
+        assert_eq!(teensy.sys.writes.len(), 1);
+    }
+
+    #[test]
+    fn program_only_skips_erased_blocks() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut image = vec![0xFF; 4 * 1024];
+        // Zeros are real data, not what the erase leaves behind
+        image[0x800..0xC00].fill(0);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let report = teensy.program(&image, |_| {}).unwrap();
+        assert_eq!(report.blocks_written, 2);
+        assert_eq!(report.blocks_skipped, 2);
+        let addrs: Vec<_> = teensy.sys.writes.iter().map(|(buf, _)| buf[1]).collect();
+        assert_eq!(addrs, vec![0x00, 0x08]);
+    }
+
+    /// Programs a small sparse image on `mcu` and boots it, then compares every report sent with
+    /// `tests/golden/<mcu>.txt`, which holds one report per line in hex.
+    ///
+    /// When the wire format changes on purpose, run the tests with `UPDATE_GOLDEN=1` set to
+    /// rewrite the files, and check the diff.
+    fn check_golden(mcu: &str) {
+        let path = format!("{}/tests/golden/{}.txt", env!("CARGO_MANIFEST_DIR"), mcu);
+        let mcu = crate::parse_mcu(mcu).unwrap();
+        let image = sparse_image(mcu.block_size, 4, &[0, 2]);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.program(&image, |_| {}).unwrap();
+        teensy.boot().unwrap();
+        let captured: String = teensy
+            .sys
+            .writes
+            .iter()
+            .map(|(buf, _)| {
+                let mut line: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+                line.push('\n');
+                line
+            })
+            .collect();
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &captured).unwrap();
+            return;
+        }
+        let golden = std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("failed to read {}: {}", path, err));
+        for (n, (captured, golden)) in captured.lines().zip(golden.lines()).enumerate() {
+            assert_eq!(captured, golden, "report {} differs from {}", n, path);
+        }
+        assert_eq!(
+            captured.lines().count(),
+            golden.lines().count(),
+            "wrong number of reports compared to {}",
+            path
+        );
+    }
+
+    #[test]
+    fn golden_avr() {
+        check_golden("TEENSY2");
+    }
+
+    #[test]
+    fn golden_512_byte_blocks() {
+        check_golden("TEENSYLC");
+    }
+
+    #[test]
+    fn golden_1024_byte_blocks() {
+        check_golden("TEENSY31");
+    }
+
+    #[test]
+    fn program_avr_reconnects_after_erase() {
+        let mcu = crate::parse_mcu("TEENSY2").unwrap();
+        let image = sparse_image(128, 4, &[0, 1, 3]);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.disconnect_after = Some(0);
+        let report = teensy.program(&image, |_| {}).unwrap();
+        assert_eq!(teensy.sys.reconnects, 1);
+        assert_eq!(report.blocks_written, 3);
+    }
+
+    #[test]
+    fn program_disconnected() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let image = sparse_image(1024, 4, &[0, 1, 2, 3]);
+        // There is no continuing without the device
+        let options = ProgramOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.unplugged_at = Some(2);
+        assert_eq!(
+            teensy.program_with(&image, &options, |_| {}),
+            Err(ProgramError::Disconnected {
+                last_addr: Some(0x400)
+            })
+        );
+        assert_eq!(teensy.sys.writes.len(), 3);
+
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.unplugged_at = Some(0);
+        assert_eq!(
+            teensy.program(&image, |_| {}),
+            Err(ProgramError::Disconnected { last_addr: None })
+        );
+    }
+
+    #[test]
+    fn program_inter_block_delay() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let image = sparse_image(1024, 4, &[0, 1, 3]);
+        let options = ProgramOptions {
+            inter_block_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        // Only between blocks, not before the first or after the last
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        let report = teensy.program_with(&image, &options, |_| {}).unwrap();
+        assert_eq!(report.blocks_written, 3);
+        assert_eq!(teensy.sys.pauses, vec![Duration::from_millis(50); 2]);
+
+        // Nor after a block that failed, the delay only follows an accepted block
+        let options = ProgramOptions {
+            continue_on_error: true,
+            ..options
+        };
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.sys.failing = vec![1];
+        let res = teensy.program_with(&image, &options, |_| {});
+        assert!(matches!(res, Err(ProgramError::PartialFailure(_))));
+        assert_eq!(teensy.sys.writes.len(), 3);
+        assert_eq!(teensy.sys.pauses, vec![Duration::from_millis(50)]);
+    }
+
+    #[test]
+    fn erase_writes_blank_first_block() {
+        let mcu = crate::parse_mcu("TEENSY31").unwrap();
+        let mut teensy = Teensy::connect(mcu).unwrap();
+        teensy.erase().unwrap();
+
+        assert_eq!(teensy.sys.writes.len(), 1);
+        let (buf, timeout) = &teensy.sys.writes[0];
+        assert_eq!(buf.len(), 64 + 1024);
+        assert!(buf[..64].iter().all(|&b| b == 0));
+        assert!(buf[64..].iter().all(|&b| b == 0xFF));
+        assert_eq!(*timeout, Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn boot_command() {
+        for &(name, block_size) in &[("TEENSY2", 128), ("TEENSY31", 1024)] {
+            let mcu = crate::parse_mcu(name).unwrap();
+            let mut teensy = Teensy::connect(mcu).unwrap();
+            teensy.boot().unwrap();
+
+            assert_eq!(teensy.sys.writes.len(), 1);
+            let (buf, _) = &teensy.sys.writes[0];
+            assert_eq!(buf.len(), block_size + header_size(block_size), "{}", name);
+            assert_eq!(&buf[..3], &[0xFF, 0xFF, 0xFF], "{}", name);
+            assert!(buf[3..].iter().all(|&b| b == 0), "{}", name);
+        }
     }
 }
@@ -1,6 +1,7 @@
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+use log::{debug, trace};
 use rusb::{DeviceHandle, GlobalContext, UsbContext};
 
 use crate::usb::*;
@@ -23,18 +24,44 @@ impl From<rusb::Error> for ConnectError {
     }
 }
 
+impl From<rusb::Error> for RebootError {
+    fn from(err: rusb::Error) -> Self {
+        RebootError::System(err.into())
+    }
+}
+
+pub(crate) const REPORT_ID_PLACEMENT: ReportIdPlacement = ReportIdPlacement::Setup;
+
 pub struct SysTeensy {
     teensy_handle: DeviceHandle<GlobalContext>,
+    /// Whether `connect` detached a kernel driver from interface 0, to be reattached on drop.
+    detached: bool,
+    /// Times the last write was retried.
+    last_write_retries: usize,
+    /// What `connect` was given, for `reconnect`.
+    vid: u16,
+    pid: u16,
+    path: Option<UsbPath>,
 }
 
 impl SysTeensy {
-    pub fn connect(vid: u16, pid: u16) -> Result<Self, ConnectError> {
+    pub fn connect(vid: u16, pid: u16, options: &ConnectOptions) -> Result<Self, ConnectError> {
+        let path = options.path;
         let mut context = GlobalContext {};
-        let mut device = open_usb_device(&mut context, vid, pid)?;
+        let mut device = open_usb_device(&mut context, vid, pid, path)?;
+        let mut detached = false;
         match device.kernel_driver_active(0) {
-            Ok(true) => {
-                device.detach_kernel_driver(0)?;
-            }
+            Ok(true) => match device.detach_kernel_driver(0) {
+                Ok(()) => detached = true,
+                // usbhid may not have actually grabbed the interface, so claiming can still work
+                Err(err @ rusb::Error::Access) | Err(err @ rusb::Error::NotSupported) => {
+                    debug!(
+                        "failed to detach kernel driver ({}), trying to claim the interface anyway",
+                        err
+                    );
+                }
+                Err(err) => return Err(ConnectError::System(SystemError::LibUsb(err))),
+            },
             Ok(false) | Err(rusb::Error::NotSupported) => {}
             Err(err) => return Err(ConnectError::System(SystemError::LibUsb(err))),
         }
@@ -43,10 +70,21 @@ impl SysTeensy {
 
         Ok(SysTeensy {
             teensy_handle: device,
+            detached,
+            last_write_retries: 0,
+            vid,
+            pid,
+            path,
         })
     }
 
-    pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<(), WriteError> {
+    pub fn write(
+        &mut self,
+        buf: &[u8],
+        protocol: &BootloaderProtocol,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError> {
         fn time_left(begin: Instant, timeout: Duration) -> Duration {
             let passed = begin.elapsed();
             if passed < timeout {
@@ -56,42 +94,242 @@ impl SysTeensy {
             }
         }
 
+        let buf = report_bytes(buf, protocol.report_id(), REPORT_ID_PLACEMENT);
+        self.last_write_retries = 0;
         let begin = Instant::now();
         while begin.elapsed() < timeout {
-            let num_written = match self.teensy_handle.write_control(
-                0x21,
-                9,
-                0x0200,
-                0,
-                buf,
+            let res = self.teensy_handle.write_control(
+                protocol.request_type,
+                protocol.request,
+                protocol.value,
+                protocol.index,
+                &buf,
                 time_left(begin, timeout),
-            ) {
+            );
+            trace!("write_control returned {:?}", res);
+            let num_written = match res {
                 Ok(n) => n,
                 Err(rusb::Error::Timeout) => 0,
+                Err(rusb::Error::NoDevice) => return Err(WriteError::Disconnected),
                 Err(err) => return Err(WriteError::System(SystemError::LibUsb(err))),
             };
 
             if num_written >= buf.len() {
                 return Ok(());
             }
-            sleep(Duration::from_millis(10));
+            self.last_write_retries += 1;
+            sleep(retry_interval);
         }
         Err(WriteError::Timeout)
     }
+
+    pub fn last_write_retries(&self) -> usize {
+        self.last_write_retries
+    }
+
+    pub fn is_connected(&self) -> bool {
+        // GET_STATUS is answered by every device and has no side effects
+        let mut status = [0; 2];
+        let res = self.teensy_handle.read_control(
+            0x80, // device to host, standard request, device recipient
+            0x00, // GET_STATUS
+            0,
+            0,
+            &mut status,
+            Duration::from_millis(100),
+        );
+        trace!("GET_STATUS returned {:?}", res);
+        res != Err(rusb::Error::NoDevice)
+    }
+
+    pub fn output_report_len(&mut self) -> Result<usize, SystemError> {
+        let mut desc = [0; 1024];
+        let len = self.teensy_handle.read_control(
+            0x81,   // device to host, standard request, interface recipient
+            0x06,   // GET_DESCRIPTOR
+            0x2200, // HID report descriptor, index 0
+            0,
+            &mut desc,
+            Duration::from_millis(500),
+        )?;
+        trace!("report descriptor: {:02x?}", &desc[..len]);
+        Ok(output_report_len(&desc[..len]))
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), ConnectError> {
+        // The device gets a new address when it comes back, so this only finds it again if no
+        // path was given
+        let options = ConnectOptions {
+            path: self.path,
+            ..Default::default()
+        };
+        *self = SysTeensy::connect(self.vid, self.pid, &options)?;
+        Ok(())
+    }
+}
+
+impl Drop for SysTeensy {
+    fn drop(&mut self) {
+        // Hand the device back as we found it, or usbhid won't see it again until it is replugged.
+        // Once it has rebooted out of the bootloader these fail with NoDevice, which is fine.
+        let res = self.teensy_handle.release_interface(0);
+        trace!("release_interface returned {:?}", res);
+        if self.detached {
+            let res = self.teensy_handle.attach_kernel_driver(0);
+            trace!("attach_kernel_driver returned {:?}", res);
+        }
+    }
+}
+
+pub fn reboot_application(vid: u16, pid: u16) -> Result<(), RebootError> {
+    let mut context = GlobalContext {};
+    let mut device = match open_usb_device(&mut context, vid, pid, None) {
+        Ok(device) => device,
+        Err(ConnectError::System(err)) => return Err(RebootError::System(err)),
+        Err(_) => return Err(RebootError::DeviceNotFound),
+    };
+
+    // The serial emulation interface's number depends on the USB type, so look for its usage page.
+    // The other HID interfaces, such as the keyboard's, accept the report too but ignore it.
+    let config = device.device().active_config_descriptor()?;
+    for iface in config.interfaces() {
+        let number = iface.number();
+        if !iface.descriptors().any(|desc| desc.class_code() == 3) {
+            continue;
+        }
+
+        // usbhid has to let go of the interface before we can send it requests
+        let detached = device.kernel_driver_active(number) == Ok(true)
+            && device.detach_kernel_driver(number).is_ok();
+        let mut desc = [0; 1024];
+        let page = device
+            .read_control(
+                0x81,   // device to host, standard request, interface recipient
+                0x06,   // GET_DESCRIPTOR
+                0x2200, // HID report descriptor, index 0
+                number as u16,
+                &mut desc,
+                Duration::from_millis(500),
+            )
+            .map(|len| usage_page(&desc[..len]));
+        trace!("interface {} usage page {:x?}", number, page);
+        if page == Ok(Some(SEREMU_USAGE_PAGE)) {
+            let res = device.write_control(
+                0x21,   // host to device, class request, interface recipient
+                9,      // SET_REPORT
+                0x0300, // feature report, ID 0
+                number as u16,
+                &SEREMU_REBOOT_REPORT,
+                Duration::from_millis(500),
+            );
+            trace!("reboot request to interface {} returned {:?}", number, res);
+            if res.is_ok() {
+                return Ok(());
+            }
+        }
+        if detached {
+            let _ = device.attach_kernel_driver(number);
+        }
+    }
+
+    Err(RebootError::Rejected)
+}
+
+pub fn list_devices(vid: u16, pid: u16) -> Result<Vec<UsbPath>, ConnectError> {
+    let context = GlobalContext {};
+    let mut paths = Vec::new();
+    for device in context.devices()?.iter() {
+        let desc = device.device_descriptor()?;
+        if desc.vendor_id() == vid && desc.product_id() == pid {
+            paths.push(UsbPath {
+                bus: device.bus_number(),
+                address: device.address(),
+            });
+        }
+    }
+    Ok(paths)
+}
+
+/// The short items of a HID report descriptor, as their tag and type bits with the value they
+/// carry. Long items are skipped.
+fn hid_items(desc: &[u8]) -> impl Iterator<Item = (u8, usize)> + '_ {
+    let mut i = 0;
+    std::iter::from_fn(move || loop {
+        let prefix = *desc.get(i)?;
+        if prefix == 0xFE {
+            // Long item: the data size follows the prefix, then a tag byte and the data
+            i += 3 + desc.get(i + 1).copied().unwrap_or(0) as usize;
+            continue;
+        }
+
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let value = desc
+            .get(i + 1..i + 1 + size)
+            .unwrap_or(&[])
+            .iter()
+            .rev()
+            .fold(0, |v, &b| (v << 8) | b as usize);
+        i += 1 + size;
+        return Some((prefix & 0xFC, value));
+    })
+}
+
+/// Adds up the size of every Output item in a HID report descriptor, in bytes.
+fn output_report_len(desc: &[u8]) -> usize {
+    let mut report_size = 0;
+    let mut report_count = 0;
+    let mut bits = 0;
+    for (item, value) in hid_items(desc) {
+        match item {
+            0x74 => report_size = value,
+            0x94 => report_count = value,
+            0x90 => bits += report_size * report_count,
+            _ => {}
+        }
+    }
+
+    bits.div_ceil(8)
+}
+
+/// The first Usage Page a HID report descriptor sets, which is that of its top level collection.
+fn usage_page(desc: &[u8]) -> Option<u16> {
+    hid_items(desc)
+        .find(|&(item, _)| item == 0x04)
+        .map(|(_, value)| value as u16)
 }
 
 fn open_usb_device<C: UsbContext>(
     context: &mut C,
     vid: u16,
     pid: u16,
+    path: Option<UsbPath>,
 ) -> Result<DeviceHandle<C>, ConnectError> {
+    // Set if we see a Teensy running a sketch rather than the bootloader
+    let mut found_app = false;
     for device in context.devices()?.iter() {
         let desc = device.device_descriptor()?;
 
         if desc.vendor_id() == vid && desc.product_id() == pid {
-            return Ok(device.open()?);
+            let at = UsbPath {
+                bus: device.bus_number(),
+                address: device.address(),
+            };
+            if path.is_none() || path == Some(at) {
+                return Ok(device.open()?);
+            }
+            trace!("skipping bootloader at {}:{}", at.bus, at.address);
+        } else if desc.vendor_id() == vid && TEENSY_APP_PRODUCT_IDS.contains(&desc.product_id()) {
+            trace!("found {:04x}:{:04x}", desc.vendor_id(), desc.product_id());
+            found_app = true;
         }
     }
 
-    Err(ConnectError::DeviceNotFound)
+    if found_app {
+        Err(ConnectError::FoundButNotInBootloader)
+    } else {
+        Err(ConnectError::DeviceNotFound)
+    }
 }
@@ -2,16 +2,49 @@ use std::time::Duration;
 
 use crate::usb::*;
 
+/// `IOHIDDeviceSetReport` takes the report ID as its own argument.
+pub(crate) const REPORT_ID_PLACEMENT: ReportIdPlacement = ReportIdPlacement::Setup;
+
 pub struct SysTeensy;
 
 impl SysTeensy {
-    pub fn connect(vid: u16, pid: u16) -> Result<Self, ConnectError> {
+    pub fn connect(vid: u16, pid: u16, options: &ConnectOptions) -> Result<Self, ConnectError> {
         unimplemented!()
     }
 
-    pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<(), WriteError> {
+    pub fn write(
+        &mut self,
+        buf: &[u8],
+        protocol: &BootloaderProtocol,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError> {
         unimplemented!()
     }
+
+    pub fn last_write_retries(&self) -> usize {
+        unimplemented!()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        unimplemented!()
+    }
+
+    pub fn output_report_len(&mut self) -> Result<usize, SystemError> {
+        unimplemented!()
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), ConnectError> {
+        unimplemented!()
+    }
+}
+
+pub fn list_devices(vid: u16, pid: u16) -> Result<Vec<UsbPath>, ConnectError> {
+    unimplemented!()
+}
+
+pub fn reboot_application(vid: u16, pid: u16) -> Result<(), RebootError> {
+    unimplemented!()
 }
 
 impl Drop for SysTeensy {
@@ -2,20 +2,97 @@ use std::time::Duration;
 
 use crate::usb::*;
 
-pub struct SysTeensy;
+#[derive(Debug, PartialEq)]
+pub enum SystemError {}
+
+/// Mock backend that records every write instead of talking to a device.
+pub struct SysTeensy {
+    pub writes: Vec<(Vec<u8>, Duration)>,
+    pub report_len: usize,
+    /// Indices of writes that should time out. They are still recorded in `writes`.
+    pub failing: Vec<usize>,
+    pub connected: bool,
+    /// Index of the write after which the device drops off the bus until `reconnect` is called.
+    pub disconnect_after: Option<usize>,
+    pub reconnects: usize,
+    /// Index of the first write that finds the device gone for good. It and every write after it
+    /// fail with `WriteError::Disconnected`.
+    pub unplugged_at: Option<usize>,
+    /// How many retries writes took, by index. Unlisted writes went through first time.
+    pub retries: Vec<(usize, usize)>,
+    /// Every pause between blocks, in order.
+    pub pauses: Vec<Duration>,
+}
 
 impl SysTeensy {
-    pub fn connect(vid: u16, pid: u16) -> Result<Self, ConnectError> {
-        unimplemented!()
+    pub fn connect(_vid: u16, _pid: u16, _options: &ConnectOptions) -> Result<Self, ConnectError> {
+        Ok(SysTeensy {
+            writes: Vec::new(),
+            report_len: 0,
+            failing: Vec::new(),
+            connected: true,
+            disconnect_after: None,
+            reconnects: 0,
+            unplugged_at: None,
+            retries: Vec::new(),
+            pauses: Vec::new(),
+        })
     }
 
-    pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<(), WriteError> {
-        unimplemented!()
+    pub fn write(
+        &mut self,
+        buf: &[u8],
+        _protocol: &BootloaderProtocol,
+        timeout: Duration,
+        _retry_interval: Duration,
+    ) -> Result<(), WriteError> {
+        let n = self.writes.len();
+        self.writes.push((buf.to_vec(), timeout));
+        if self.disconnect_after == Some(n) {
+            self.connected = false;
+        }
+        if self.unplugged_at.is_some_and(|at| n >= at) {
+            Err(WriteError::Disconnected)
+        } else if self.failing.contains(&n) {
+            Err(WriteError::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn last_write_retries(&self) -> usize {
+        let last = self.writes.len().wrapping_sub(1);
+        self.retries
+            .iter()
+            .find(|&&(n, _)| n == last)
+            .map_or(0, |&(_, retries)| retries)
     }
-}
 
-impl Drop for SysTeensy {
-    fn drop(&mut self) {
-        unimplemented!()
+    pub fn pause(&mut self, duration: Duration) {
+        self.pauses.push(duration);
     }
+
+    pub fn output_report_len(&mut self) -> Result<usize, SystemError> {
+        Ok(self.report_len)
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), ConnectError> {
+        self.connected = true;
+        self.reconnects += 1;
+        Ok(())
+    }
+}
+
+/// There are no devices to list.
+pub fn list_devices(_vid: u16, _pid: u16) -> Result<Vec<UsbPath>, ConnectError> {
+    Ok(Vec::new())
+}
+
+/// There are no devices to reboot.
+pub fn reboot_application(_vid: u16, _pid: u16) -> Result<(), RebootError> {
+    Err(RebootError::DeviceNotFound)
 }
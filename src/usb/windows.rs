@@ -3,7 +3,9 @@ use std::ptr::{null, null_mut};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
+use log::{debug, trace};
 use winapi::ctypes::c_void;
+use winapi::shared::hidpi::*;
 use winapi::shared::hidsdi::*;
 use winapi::shared::minwindef::*;
 use winapi::shared::winerror::*;
@@ -23,24 +25,65 @@ use crate::usb::*;
 pub enum SystemError {
     CreateHandle,
     IoPending,
+    GetCaps,
     NoBytesWritten,
     OverlapError,
+    PreparsedData,
+    /// `SetupDiGetDeviceInterfaceDetailA` failed, with the code from `GetLastError`.
+    DeviceDetail(DWORD),
 }
 
+/// `WriteFile`, `HidD_SetOutputReport` and `HidD_SetFeature` all want the report ID as the first
+/// byte of the buffer.
+pub(crate) const REPORT_ID_PLACEMENT: ReportIdPlacement = ReportIdPlacement::Prefix;
+
 pub struct SysTeensy {
     teensy_handle: HANDLE,
+    /// Other HID collections that matched the VID/PID, tried in order if nothing has been
+    /// written successfully through `teensy_handle` yet.
+    spare_handles: Vec<HANDLE>,
+    written: bool,
     write_event: Option<HANDLE>,
+    /// Write with `HidD_SetOutputReport` instead of overlapped `WriteFile`.
+    sync: bool,
+    /// Overlapped writes that have failed in a row, other than by timing out.
+    overlapped_failures: usize,
+    /// Times the last write was retried.
+    last_write_retries: usize,
+    /// What `connect` was given, for `reconnect`.
+    vid: u16,
+    pid: u16,
 }
 
+/// Overlapped writes that may fail in a row before we give up on them and go synchronous.
+const OVERLAPPED_FAILURE_LIMIT: usize = 5;
+
 impl SysTeensy {
-    pub fn connect(vid: u16, pid: u16) -> Result<Self, ConnectError> {
+    pub fn connect(vid: u16, pid: u16, options: &ConnectOptions) -> Result<Self, ConnectError> {
+        if options.path.is_some() {
+            return Err(ConnectError::PathUnsupported);
+        }
+        let mut handles = unsafe { open_usb_device(vid, pid)? };
+        let teensy_handle = handles.remove(0);
         Ok(SysTeensy {
-            teensy_handle: unsafe { open_usb_device(vid, pid)? },
+            teensy_handle,
+            spare_handles: handles,
+            written: false,
             write_event: None,
+            sync: options.sync,
+            overlapped_failures: 0,
+            last_write_retries: 0,
+            vid,
+            pid,
         })
     }
 
-    unsafe fn __write(&mut self, buf: &[u8], timeout: u32) -> Result<(), WriteError> {
+    unsafe fn __write(
+        &mut self,
+        buf: &[u8],
+        report_id: u8,
+        timeout: u32,
+    ) -> Result<(), WriteError> {
         if let None = self.write_event {
             let event = CreateEventA(null_mut(), TRUE, TRUE, null());
             if event.is_null() {
@@ -54,22 +97,28 @@ impl SysTeensy {
 
         let mut ov = OVERLAPPED::default();
         ov.hEvent = event;
-        let mut tempbuf = vec![0];
-        tempbuf.extend(buf);
+        let tempbuf = report_bytes(buf, report_id, REPORT_ID_PLACEMENT);
 
-        if WriteFile(
+        let ret = WriteFile(
             self.teensy_handle,
             tempbuf.as_ptr() as *const c_void,
             tempbuf.len() as DWORD,
             null_mut(),
             &mut ov,
-        ) == 0
-        {
-            if GetLastError() != ERROR_IO_PENDING {
+        );
+        trace!("WriteFile returned {}", ret);
+        if ret == 0 {
+            let err = GetLastError();
+            trace!("GetLastError returned {}", err);
+            if err == ERROR_DEVICE_NOT_CONNECTED {
+                return Err(WriteError::Disconnected);
+            }
+            if err != ERROR_IO_PENDING {
                 return Err(WriteError::System(SystemError::IoPending));
             }
 
             let ret = WaitForSingleObject(event, timeout);
+            trace!("WaitForSingleObject returned {}", ret);
             if ret == WAIT_TIMEOUT {
                 CancelIo(self.teensy_handle);
                 return Err(WriteError::Timeout);
@@ -77,7 +126,12 @@ impl SysTeensy {
         }
 
         let mut n = 0;
-        if GetOverlappedResult(self.teensy_handle, &mut ov, &mut n, FALSE) == 0 {
+        let ret = GetOverlappedResult(self.teensy_handle, &mut ov, &mut n, FALSE);
+        trace!("GetOverlappedResult returned {}, {} bytes written", ret, n);
+        if ret == 0 {
+            if GetLastError() == ERROR_DEVICE_NOT_CONNECTED {
+                return Err(WriteError::Disconnected);
+            }
             return Err(WriteError::System(SystemError::OverlapError));
         }
         if n <= 0 {
@@ -87,7 +141,63 @@ impl SysTeensy {
         Ok(())
     }
 
-    pub fn write(&mut self, buf: &[u8], timeout: Duration) -> Result<(), WriteError> {
+    /// Synchronous fallback for `__write`, for HID drivers that don't cooperate with overlapped
+    /// I/O. There is no timeout, the call returns when the driver is done with the report.
+    unsafe fn __write_sync(&mut self, buf: &[u8], report_id: u8) -> Result<(), WriteError> {
+        let mut tempbuf = report_bytes(buf, report_id, REPORT_ID_PLACEMENT).into_owned();
+
+        let ret = HidD_SetOutputReport(
+            self.teensy_handle,
+            tempbuf.as_mut_ptr() as *mut c_void,
+            tempbuf.len() as ULONG,
+        );
+        trace!("HidD_SetOutputReport returned {}", ret);
+        if ret == 0 {
+            let err = GetLastError();
+            trace!("GetLastError returned {}", err);
+            if err == ERROR_DEVICE_NOT_CONNECTED {
+                return Err(WriteError::Disconnected);
+            }
+            return Err(WriteError::System(SystemError::NoBytesWritten));
+        }
+
+        Ok(())
+    }
+
+    pub fn write(
+        &mut self,
+        buf: &[u8],
+        protocol: &BootloaderProtocol,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError> {
+        self.last_write_retries = 0;
+        loop {
+            match self.write_handle(buf, protocol.report_id(), timeout, retry_interval) {
+                Err(WriteError::Timeout) if !self.written && !self.spare_handles.is_empty() => {
+                    // We may have picked a collection that isn't HalfKay's, try the next one
+                    trace!("write timed out, trying the next matching HID collection");
+                    unsafe {
+                        CloseHandle(self.teensy_handle);
+                    }
+                    self.teensy_handle = self.spare_handles.remove(0);
+                }
+                Ok(()) => {
+                    self.written = true;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn write_handle(
+        &mut self,
+        buf: &[u8],
+        report_id: u8,
+        timeout: Duration,
+        retry_interval: Duration,
+    ) -> Result<(), WriteError> {
         fn time_left(begin: Instant, timeout: Duration) -> Duration {
             let passed = begin.elapsed();
             if passed < timeout {
@@ -99,26 +209,136 @@ impl SysTeensy {
 
         let begin = Instant::now();
         while begin.elapsed() < timeout {
-            if let Ok(_) =
-                unsafe { self.__write(buf, time_left(begin, timeout).as_millis() as u32) }
-            {
-                return Ok(());
+            let res = if self.sync {
+                unsafe { self.__write_sync(buf, report_id) }
+            } else {
+                unsafe {
+                    self.__write(buf, report_id, time_left(begin, timeout).as_millis() as u32)
+                }
+            };
+            match res {
+                Ok(()) => {
+                    self.overlapped_failures = 0;
+                    return Ok(());
+                }
+                // Retrying a stale handle won't bring the device back
+                Err(WriteError::Disconnected) => return Err(WriteError::Disconnected),
+                Err(WriteError::System(_)) if !self.sync => {
+                    self.overlapped_failures += 1;
+                    if self.overlapped_failures >= OVERLAPPED_FAILURE_LIMIT {
+                        debug!(
+                            "{} overlapped writes failed in a row, switching to synchronous writes",
+                            self.overlapped_failures
+                        );
+                        self.sync = true;
+                    }
+                }
+                Err(_) => {}
             }
-            sleep(Duration::from_millis(10));
+            self.last_write_retries += 1;
+            sleep(retry_interval);
         }
         Err(WriteError::Timeout)
     }
+
+    pub fn last_write_retries(&self) -> usize {
+        self.last_write_retries
+    }
+
+    pub fn is_connected(&self) -> bool {
+        // Fails once the device has gone away, without touching the bus
+        let mut attrib = HIDD_ATTRIBUTES::default();
+        attrib.Size = size_of::<HIDD_ATTRIBUTES>() as ULONG;
+        let ret = unsafe { HidD_GetAttributes(self.teensy_handle, &mut attrib) };
+        trace!("HidD_GetAttributes returned {}", ret);
+        ret != 0
+    }
+
+    pub fn output_report_len(&mut self) -> Result<usize, SystemError> {
+        let caps = unsafe { hid_caps(self.teensy_handle)? };
+        // Windows counts the report ID byte we prepend in `__write`
+        Ok((caps.OutputReportByteLength as usize).saturating_sub(1))
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), ConnectError> {
+        // Don't make the new handle find out about the driver all over again
+        let options = ConnectOptions {
+            sync: self.sync,
+            ..Default::default()
+        };
+        *self = SysTeensy::connect(self.vid, self.pid, &options)?;
+        Ok(())
+    }
 }
 
 impl Drop for SysTeensy {
     fn drop(&mut self) {
         unsafe {
             CloseHandle(self.teensy_handle);
+            for &h in &self.spare_handles {
+                CloseHandle(h);
+            }
         }
     }
 }
 
-unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<HANDLE, ConnectError> {
+/// HID device paths don't say where a device is plugged in.
+pub fn list_devices(_vid: u16, _pid: u16) -> Result<Vec<UsbPath>, ConnectError> {
+    Err(ConnectError::PathUnsupported)
+}
+
+pub fn reboot_application(vid: u16, pid: u16) -> Result<(), RebootError> {
+    let handles = match unsafe { open_usb_device(vid, pid) } {
+        Ok(handles) => handles,
+        Err(ConnectError::System(err)) => return Err(RebootError::System(err)),
+        Err(_) => return Err(RebootError::DeviceNotFound),
+    };
+
+    // Each HID interface is its own collection. The others, such as the keyboard's, accept the
+    // report too but ignore it, so only send it to the serial emulation one.
+    let mut report = report_bytes(&SEREMU_REBOOT_REPORT, 0, REPORT_ID_PLACEMENT).into_owned();
+    let mut rebooted = false;
+    for h in handles {
+        unsafe {
+            let seremu = hid_caps(h).is_ok_and(|caps| caps.UsagePage == SEREMU_USAGE_PAGE);
+            if !rebooted && seremu {
+                let ret =
+                    HidD_SetFeature(h, report.as_mut_ptr() as *mut c_void, report.len() as ULONG);
+                trace!("HidD_SetFeature returned {}", ret);
+                rebooted = ret != 0;
+            }
+            CloseHandle(h);
+        }
+    }
+
+    if rebooted {
+        Ok(())
+    } else {
+        Err(RebootError::Rejected)
+    }
+}
+
+unsafe fn hid_caps(handle: HANDLE) -> Result<HIDP_CAPS, SystemError> {
+    let mut preparsed = null_mut();
+    if HidD_GetPreparsedData(handle, &mut preparsed) == 0 {
+        return Err(SystemError::PreparsedData);
+    }
+    let mut caps = HIDP_CAPS::default();
+    let status = HidP_GetCaps(preparsed, &mut caps);
+    HidD_FreePreparsedData(preparsed);
+    trace!("HidP_GetCaps returned {:#x}", status);
+    if status != HIDP_STATUS_SUCCESS {
+        return Err(SystemError::GetCaps);
+    }
+    Ok(caps)
+}
+
+/// Opens every HID collection matching `vid` and `pid`.
+///
+/// A Teensy can expose more than one collection, and HalfKay's isn't necessarily enumerated
+/// first. Its output report carries a whole block, so the handles are sorted by output report
+/// length, longest first, to put the programming collection at the front.
+unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<Vec<HANDLE>, ConnectError> {
     let mut guid = Default::default();
     HidD_GetHidGuid(&mut guid);
 
@@ -132,6 +352,11 @@ unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<HANDLE, ConnectError> {
         return Err(ConnectError::System(SystemError::CreateHandle));
     }
 
+    // Set if we see a Teensy running a sketch rather than the bootloader
+    let mut found_app = false;
+    // Reported if no device turns up, since it may have been the one we couldn't look at
+    let mut detail_error = None;
+    let mut handles = Vec::new();
     let mut index = 0;
     loop {
         let mut iface = SP_DEVICE_INTERFACE_DATA::default();
@@ -142,39 +367,20 @@ unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<HANDLE, ConnectError> {
         }
         index += 1;
 
-        let mut required_size = 0;
-        SetupDiGetDeviceInterfaceDetailA(
-            info,
-            &mut iface,
-            null_mut(),
-            0,
-            &mut required_size,
-            null_mut(),
-        );
-
-        // malloc `details`
-        let mut details_buf = Vec::<u8>::with_capacity(required_size as usize);
-        details_buf.resize(required_size as usize, 0);
-
-        let details = details_buf.as_mut_ptr() as PSP_DEVICE_INTERFACE_DETAIL_DATA_A;
-        (*details).cbSize = size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_A>() as DWORD;
-        if SetupDiGetDeviceInterfaceDetailA(
-            info,
-            &mut iface,
-            details,
-            required_size,
-            null_mut(),
-            null_mut(),
-        ) == 0
-        {
-            // free `details`
-            Vec::from_raw_parts(
-                details as *mut u8,
-                required_size as usize,
-                required_size as usize,
-            );
-            continue;
-        }
+        let details_buf = match interface_detail(info, &mut iface) {
+            Ok(buf) => buf,
+            Err(err) => {
+                debug!(
+                    "SetupDiGetDeviceInterfaceDetailA failed for interface {}: {}",
+                    index - 1,
+                    err
+                );
+                detail_error = Some(err);
+                continue;
+            }
+        };
+        // Only valid while `details_buf` is, which outlives the one use below
+        let details = details_buf.as_ptr() as PSP_DEVICE_INTERFACE_DETAIL_DATA_A;
 
         let h = CreateFileA(
             (*details).DevicePath.as_ptr(),
@@ -185,14 +391,6 @@ unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<HANDLE, ConnectError> {
             FILE_FLAG_OVERLAPPED,
             null_mut(),
         );
-        {
-            // free `details`
-            Vec::from_raw_parts(
-                details as *mut u8,
-                required_size as usize,
-                required_size as usize,
-            );
-        }
 
         if h == INVALID_HANDLE_VALUE {
             continue;
@@ -205,13 +403,98 @@ unsafe fn open_usb_device(vid: u16, pid: u16) -> Result<HANDLE, ConnectError> {
             continue;
         }
         if attrib.VendorID != vid || attrib.ProductID != pid {
+            found_app |=
+                attrib.VendorID == vid && TEENSY_APP_PRODUCT_IDS.contains(&attrib.ProductID);
             CloseHandle(h);
             continue;
         }
 
-        SetupDiDestroyDeviceInfoList(info);
-        return Ok(h);
+        let report_len = hid_caps(h).map_or(0, |caps| caps.OutputReportByteLength);
+        trace!(
+            "matching HID collection with {} byte output reports",
+            report_len
+        );
+        handles.push((report_len, h));
+    }
+
+    if !handles.is_empty() {
+        handles.sort_by(|a, b| b.0.cmp(&a.0));
+        return Ok(handles.into_iter().map(|(_, h)| h).collect());
     }
 
-    Err(ConnectError::DeviceNotFound)
+    if found_app {
+        Err(ConnectError::FoundButNotInBootloader)
+    } else if let Some(err) = detail_error {
+        Err(ConnectError::System(SystemError::DeviceDetail(err)))
+    } else {
+        Err(ConnectError::DeviceNotFound)
+    }
+}
+
+/// How many times to ask for an interface's details when its size keeps changing under us.
+const DETAIL_ATTEMPTS: usize = 3;
+
+/// Gets the `SP_DEVICE_INTERFACE_DETAIL_DATA_A` for `iface`, returned in the buffer holding it.
+///
+/// The struct is a `DWORD` `cbSize` followed by `DevicePath`, a nul terminated string declared as
+/// a one element array that really runs on to the end of the buffer. So it can't live on the stack:
+/// the size is queried first and the details fetched into a buffer that big with a second call.
+/// `cbSize` has to be set to the size of the declared struct, not the buffer, for the call to be
+/// accepted. The buffer is made of `DWORD`s so that `cbSize` is aligned.
+///
+/// A device re-enumerating between the two calls can make the second fail with
+/// `ERROR_INSUFFICIENT_BUFFER`, in which case we start over. Any other failure is returned with
+/// its `GetLastError` code.
+unsafe fn interface_detail(
+    info: HDEVINFO,
+    iface: &mut SP_DEVICE_INTERFACE_DATA,
+) -> Result<Vec<DWORD>, DWORD> {
+    let mut err = ERROR_INSUFFICIENT_BUFFER;
+    for attempt in 0..DETAIL_ATTEMPTS {
+        let mut required_size = 0;
+        if SetupDiGetDeviceInterfaceDetailA(
+            info,
+            iface,
+            null_mut(),
+            0,
+            &mut required_size,
+            null_mut(),
+        ) == 0
+        {
+            // Expected, there was no buffer to fill
+            let err = GetLastError();
+            if err != ERROR_INSUFFICIENT_BUFFER {
+                return Err(err);
+            }
+        }
+
+        // Never smaller than the declared struct, or setting `cbSize` would write past the end
+        let struct_size = size_of::<SP_DEVICE_INTERFACE_DETAIL_DATA_A>();
+        let len = (required_size as usize).max(struct_size);
+        let mut details_buf = vec![0 as DWORD; len.div_ceil(size_of::<DWORD>())];
+        let details = details_buf.as_mut_ptr() as PSP_DEVICE_INTERFACE_DETAIL_DATA_A;
+        (*details).cbSize = struct_size as DWORD;
+        if SetupDiGetDeviceInterfaceDetailA(
+            info,
+            iface,
+            details,
+            len as DWORD,
+            null_mut(),
+            null_mut(),
+        ) != 0
+        {
+            return Ok(details_buf);
+        }
+
+        err = GetLastError();
+        if err != ERROR_INSUFFICIENT_BUFFER {
+            return Err(err);
+        }
+        debug!(
+            "interface detail size changed from {} bytes, attempt {}",
+            required_size,
+            attempt + 1
+        );
+    }
+    Err(err)
 }
@@ -0,0 +1,18 @@
+use rusty_loader::{load_file, parse_mcu, FileHint};
+
+// blink_avr.ihex is a Teensy 2.0 blink in the classic AVR form: 16-bit `Data` records only, with
+// no extended address records. A `rjmp` at 0 jumps to the loop at 0x100.
+#[test]
+fn avr_ihex_layout() {
+    let mcu = parse_mcu("TEENSY2").unwrap();
+    let (binary, touched, file_type) = load_file("tests/blink_avr.ihex", FileHint::Any, &mcu)
+        .expect("Failed to load Intel hex file");
+
+    assert_eq!(file_type, FileHint::IHEX);
+    assert_eq!(binary.len(), mcu.code_size);
+    assert_eq!(touched.written(), 18);
+    assert_eq!(&binary[..2], &[0x7F, 0xC0]);
+    assert!(binary[2..0x100].iter().all(|&b| b == 0xFF));
+    assert_eq!(&binary[0x100..0x104], &[0x56, 0x9A, 0x4E, 0x9A]);
+    assert!(binary[0x110..].iter().all(|&b| b == 0xFF));
+}
@@ -0,0 +1,69 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use rusty_loader::parse_mcu;
+use rusty_loader::usb::{
+    BootloaderProtocol, ConnectError, ConnectOptions, HalfKayTransport, SystemError, Teensy,
+    WriteError,
+};
+
+#[derive(Debug, PartialEq)]
+enum Transaction {
+    ReportLen,
+    /// A write, by the address in its header. The boot command is addressed 0xFFFFFF.
+    Write(usize),
+    Reconnect,
+}
+
+/// Transport that records what it is asked to do, shared with the test so it can still be read
+/// once `Teensy` owns the transport.
+struct Recorder(Rc<RefCell<Vec<Transaction>>>);
+
+impl HalfKayTransport for Recorder {
+    fn write(
+        &mut self,
+        buf: &[u8],
+        _protocol: &BootloaderProtocol,
+        _timeout: Duration,
+        _retry_interval: Duration,
+    ) -> Result<(), WriteError> {
+        let addr = buf[0] as usize | (buf[1] as usize) << 8 | (buf[2] as usize) << 16;
+        self.0.borrow_mut().push(Transaction::Write(addr));
+        Ok(())
+    }
+
+    fn output_report_len(&mut self) -> Result<usize, SystemError> {
+        self.0.borrow_mut().push(Transaction::ReportLen);
+        Ok(1024 + 64)
+    }
+
+    fn reconnect(&mut self) -> Result<(), ConnectError> {
+        self.0.borrow_mut().push(Transaction::Reconnect);
+        Ok(())
+    }
+}
+
+// Detecting the part, programming and booting all go through the one handle, in order
+#[test]
+fn one_connection_for_everything() {
+    let mcu = parse_mcu("TEENSY31").unwrap();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut teensy =
+        Teensy::new_from_handle(Recorder(log.clone()), mcu, &ConnectOptions::default()).unwrap();
+
+    assert_eq!(teensy.detect_block_size(), Ok(1024));
+    // The first and third blocks, the others are blank
+    let mut image = vec![0xFF; 4 * 1024];
+    image[0] = 0;
+    image[2 * 1024] = 0;
+    let report = teensy.program(&image, |_| {}).unwrap();
+    assert_eq!(report.blocks_written, 2);
+    teensy.boot().unwrap();
+
+    use Transaction::*;
+    assert_eq!(
+        *log.borrow(),
+        vec![ReportLen, Write(0), Write(0x800), Write(0xFF_FFFF)]
+    );
+}
@@ -1,14 +1,40 @@
-use rusty_loader::{load_file, parse_mcu, FileHint};
+use rusty_loader::{load_file, parse_image, parse_mcu, FileHint, LoadOptions};
 
 #[test]
 fn ihex_same_as_elf() {
     let mcu = parse_mcu("TEENSYLC").unwrap();
-    let (ihex_binary, ihex_len) =
+    let (ihex_binary, ihex_len, _) =
         load_file("tests/blink.ihex", FileHint::IHEX, &mcu).expect("Failed to load Intel hex file");
-    let (elf_binary, elf_len) =
+    let (elf_binary, elf_len, _) =
         load_file("tests/blink", FileHint::ELF, &mcu).expect("Failed to load ELF file");
 
     assert_eq!(ihex_len, elf_len);
     assert_eq!(ihex_binary.len(), elf_binary.len());
     assert_eq!(ihex_binary, elf_binary);
 }
+
+#[test]
+fn detects_file_type() {
+    let mcu = parse_mcu("TEENSYLC").unwrap();
+    let (_, _, ihex_type) =
+        load_file("tests/blink.ihex", FileHint::Any, &mcu).expect("Failed to load Intel hex file");
+    let (_, _, elf_type) =
+        load_file("tests/blink", FileHint::Any, &mcu).expect("Failed to load ELF file");
+
+    assert_eq!(ihex_type, FileHint::IHEX);
+    assert_eq!(elf_type, FileHint::ELF);
+}
+
+#[test]
+fn parse_image_same_as_load_file() {
+    let mcu = parse_mcu("TEENSYLC").unwrap();
+    let data = std::fs::read("tests/blink.ihex").unwrap();
+    let parsed = parse_image(&data, FileHint::Any, &mcu, &LoadOptions::default())
+        .expect("Failed to parse Intel hex file");
+    let loaded =
+        load_file("tests/blink.ihex", FileHint::Any, &mcu).expect("Failed to load Intel hex file");
+
+    assert_eq!(parsed.0, loaded.0);
+    assert_eq!(parsed.1, loaded.1);
+    assert_eq!(parsed.2, FileHint::IHEX);
+}